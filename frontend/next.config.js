@@ -14,6 +14,10 @@ const config = {
                     source: "/backend/stream/:streamId",
                     destination: `${env.BACKEND_URL}/stream/:streamId`,
                 },
+                {
+                    source: "/backend/stream/:streamId/key/:keyId",
+                    destination: `${env.BACKEND_URL}/stream/:streamId/key/:keyId`,
+                },
                 {
                     source: "/backend/segment/:streamId/:segmentId",
                     destination: `${env.BACKEND_URL}/segment/:streamId/:segmentId`,
@@ -0,0 +1,331 @@
+use axum::{
+    extract::{Path, State},
+    response::IntoResponse,
+    Json,
+};
+use hyper::StatusCode;
+use serde::Deserialize;
+use sqlx::{Pool, Sqlite};
+use tracing::{info, instrument, warn};
+
+use crate::api::data::{Broadcast, Channel, StreamAccess, Visibility};
+use crate::api::playback::authorize_playback;
+use crate::api::serve::read_playlist;
+use crate::api::tags::attach_tags;
+use crate::auth::IsAdmin;
+use crate::playback::{PlaybackAuth, PlaybackSigner};
+use crate::server::PublicUrl;
+use crate::storage::SharedStorage;
+use crate::utils::database_error;
+
+#[derive(Debug, Clone, Copy)]
+pub enum BroadcastKind {
+    Live,
+    Upload,
+}
+
+impl BroadcastKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            BroadcastKind::Live => "live",
+            BroadcastKind::Upload => "upload",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateChannel {
+    name: String,
+    #[serde(default)]
+    description: String,
+    owner: String,
+    default_width: Option<i64>,
+    default_height: Option<i64>,
+}
+
+#[instrument(skip(db))]
+pub async fn create_channel(
+    db: State<Pool<Sqlite>>,
+    Json(body): Json<CreateChannel>,
+) -> Result<Json<Channel>, StatusCode> {
+    let channel = Channel {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: body.name,
+        description: body.description,
+        owner: body.owner,
+        default_width: body.default_width,
+        default_height: body.default_height,
+        created_at: chrono::Utc::now(),
+    };
+
+    sqlx::query(
+        r#"insert into channels (id, name, description, owner, defaultWidth, defaultHeight, createdAt) values ($1, $2, $3, $4, $5, $6, $7)"#,
+    )
+    .bind(&channel.id)
+    .bind(&channel.name)
+    .bind(&channel.description)
+    .bind(&channel.owner)
+    .bind(channel.default_width)
+    .bind(channel.default_height)
+    .bind(channel.created_at)
+    .execute(&*db)
+    .await
+    .map_err(database_error)?;
+
+    info!(channel_id = channel.id, "Created channel");
+    Ok(Json(channel))
+}
+
+#[instrument(skip(db))]
+pub async fn get_channels(db: State<Pool<Sqlite>>) -> Result<Json<Vec<Channel>>, StatusCode> {
+    let channels = sqlx::query_as(r#"SELECT * FROM `channels` ORDER BY `createdAt` DESC"#)
+        .fetch_all(&*db)
+        .await
+        .map_err(database_error)?;
+
+    Ok(Json(channels))
+}
+
+#[instrument(skip(db))]
+pub async fn get_channel(
+    Path(channel_id): Path<String>,
+    db: State<Pool<Sqlite>>,
+) -> Result<Json<Channel>, StatusCode> {
+    Ok(Json(get_channel_by_id(&db, &channel_id).await?))
+}
+
+/// Serves the playlist of the channel's current live broadcast.
+#[instrument(skip(auth, db, storage, signer, public_url))]
+pub async fn live(
+    Path(channel_id): Path<String>,
+    auth: PlaybackAuth,
+    db: State<Pool<Sqlite>>,
+    State(storage): State<SharedStorage>,
+    State(signer): State<PlaybackSigner>,
+    State(public_url): State<PublicUrl>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let live: Option<(String, Visibility, bool)> = sqlx::query_as(
+        r#"SELECT b.`streamId`, s.`visibility`, s.`passwordHash` IS NOT NULL FROM `broadcasts` b JOIN `streams` s ON s.`id` = b.`streamId` WHERE b.`channelId` = $1 AND b.`kind` = 'live' AND b.`endedAt` IS NULL AND s.`deletedAt` IS NULL ORDER BY b.`startedAt` DESC LIMIT 1"#,
+    )
+    .bind(&channel_id)
+    .fetch_optional(&*db)
+    .await
+    .map_err(|err| (database_error(err), String::new()))?;
+
+    let Some((stream_id, visibility, password_protected)) = live else {
+        warn!("Channel is not live");
+        return Err((StatusCode::NOT_FOUND, "Channel is not live".to_string()));
+    };
+
+    info!(stream_id, "Serving live broadcast");
+    let access = StreamAccess {
+        visibility,
+        password_protected,
+    };
+    let token = authorize_playback(&signer, &stream_id, access, &auth)
+        .map_err(|status| (status, String::new()))?;
+    Ok(public_url.playlist(
+        &read_playlist(&storage, &stream_id).await?,
+        &format!("channels/{}/live", channel_id),
+        &stream_id,
+        token.as_deref(),
+    ))
+}
+
+/// Only public broadcasts are listed unless the request carries the admin token.
+#[instrument(skip(db))]
+pub async fn get_broadcasts(
+    Path(channel_id): Path<String>,
+    IsAdmin(admin): IsAdmin,
+    db: State<Pool<Sqlite>>,
+) -> Result<Json<Vec<Broadcast>>, StatusCode> {
+    // Make sure unknown channels are a 404 rather than an empty archive
+    get_channel_by_id(&db, &channel_id).await?;
+
+    let mut broadcasts: Vec<Broadcast> = sqlx::query_as(
+        r#"SELECT b.`id` AS `broadcastId`, b.`channelId`, b.`kind`, b.`startedAt`, b.`endedAt`, s.*
+        FROM `broadcasts` b JOIN `streams` s ON s.`id` = b.`streamId`
+        WHERE b.`channelId` = $1 AND s.`deletedAt` IS NULL AND ($2 OR s.`visibility` = $3)
+        ORDER BY b.`startedAt` DESC"#,
+    )
+    .bind(&channel_id)
+    .bind(admin)
+    .bind(Visibility::Public)
+    .fetch_all(&*db)
+    .await
+    .map_err(database_error)?;
+    attach_tags(
+        &db,
+        broadcasts.iter_mut().map(|broadcast| &mut broadcast.stream),
+    )
+    .await
+    .map_err(database_error)?;
+
+    Ok(Json(broadcasts))
+}
+
+pub async fn get_channel_by_id(db: &Pool<Sqlite>, channel_id: &str) -> Result<Channel, StatusCode> {
+    sqlx::query_as(r#"SELECT * FROM `channels` WHERE `id` = $1"#)
+        .bind(channel_id)
+        .fetch_optional(db)
+        .await
+        .map_err(database_error)?
+        .ok_or_else(|| {
+            warn!(channel_id, "Channel not found");
+            StatusCode::NOT_FOUND
+        })
+}
+
+pub async fn start_broadcast(
+    db: &Pool<Sqlite>,
+    channel_id: &str,
+    stream_id: &str,
+    kind: BroadcastKind,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"insert into broadcasts (id, channelId, streamId, kind, startedAt) values ($1, $2, $3, $4, $5)"#,
+    )
+    .bind(uuid::Uuid::new_v4().to_string())
+    .bind(channel_id)
+    .bind(stream_id)
+    .bind(kind.as_str())
+    .bind(chrono::Utc::now())
+    .execute(db)
+    .await?;
+
+    info!(channel_id, stream_id, "Started broadcast");
+    Ok(())
+}
+
+/// Marks the broadcast for a stream as finished, a no-op for streams that are
+/// not part of a channel.
+pub async fn end_broadcast(db: &Pool<Sqlite>, stream_id: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"UPDATE `broadcasts` SET `endedAt` = $1 WHERE `streamId` = $2 AND `endedAt` IS NULL"#,
+    )
+    .bind(chrono::Utc::now())
+    .bind(stream_id)
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        extract::FromRef,
+        http::{header, Request},
+        routing::{get, post},
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::auth::AdminToken;
+    use crate::utils::test_db;
+
+    #[derive(Clone, FromRef)]
+    struct TestState {
+        db: Pool<Sqlite>,
+        admin_token: AdminToken,
+    }
+
+    async fn insert_stream(db: &Pool<Sqlite>, id: &str, visibility: Visibility) {
+        sqlx::query(
+            r#"insert into streams (id, name, description, startTime, width, height, visibility) values ($1, '', '', $2, 1920, 1080, $3)"#,
+        )
+        .bind(id)
+        .bind(chrono::Utc::now())
+        .bind(visibility)
+        .execute(db)
+        .await
+        .unwrap();
+    }
+
+    async fn get_json(app: &Router, uri: &str, admin: bool) -> (StatusCode, serde_json::Value) {
+        let mut req = Request::get(uri);
+        if admin {
+            req = req.header(header::AUTHORIZATION, "Bearer admin");
+        }
+        let res = app
+            .clone()
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = res.status();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn channels_keep_every_broadcast() {
+        let db = test_db().await;
+        let app = Router::new()
+            .route("/channels", post(create_channel))
+            .route("/channels/:channelId/broadcasts", get(get_broadcasts))
+            .with_state(TestState {
+                db: db.clone(),
+                admin_token: AdminToken(Some("admin".into())),
+            });
+
+        let res = app
+            .clone()
+            .oneshot(
+                Request::post("/channels")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(r#"{"name":"Channel","owner":"me"}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let channel: Channel = serde_json::from_slice(&body).unwrap();
+
+        insert_stream(&db, "first", Visibility::Public).await;
+        start_broadcast(&db, &channel.id, "first", BroadcastKind::Live)
+            .await
+            .unwrap();
+        end_broadcast(&db, "first").await.unwrap();
+        insert_stream(&db, "second", Visibility::Private).await;
+        start_broadcast(&db, &channel.id, "second", BroadcastKind::Upload)
+            .await
+            .unwrap();
+
+        let uri = format!("/channels/{}/broadcasts", channel.id);
+        let (status, broadcasts) = get_json(&app, &uri, true).await;
+        assert_eq!(status, StatusCode::OK);
+        let broadcasts = broadcasts.as_array().unwrap();
+        assert_eq!(broadcasts.len(), 2);
+        let first = broadcasts
+            .iter()
+            .find(|b| b["stream"]["id"] == "first")
+            .unwrap();
+        assert_eq!(first["kind"], "live");
+        assert!(!first["endedAt"].is_null());
+        let second = broadcasts
+            .iter()
+            .find(|b| b["stream"]["id"] == "second")
+            .unwrap();
+        assert_eq!(second["kind"], "upload");
+        assert!(second["endedAt"].is_null());
+
+        let (_, broadcasts) = get_json(&app, &uri, false).await;
+        let ids: Vec<_> = broadcasts
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|b| &b["stream"]["id"])
+            .collect();
+        assert_eq!(ids, ["first"]);
+
+        let (status, _) = get_json(&app, "/channels/unknown/broadcasts", true).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,534 @@
+use std::path::Path as FsPath;
+use std::process::Stdio;
+
+use anyhow::anyhow;
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use hyper::StatusCode;
+use serde::Deserialize;
+use sqlx::{Pool, Sqlite};
+use tracing::{error, info, instrument, warn};
+
+use crate::api::data::{Stream, StreamAccess, Visibility};
+use crate::api::playback::authorize_playback;
+use crate::api::serve::read_playlist;
+use crate::api::tags::{attach_tags, copy_stream_tags, normalize_tags, set_stream_tags};
+use crate::auth::IsAdmin;
+use crate::encryption::{self, KeyRotation, Keys};
+use crate::events::{EventBus, StreamEvent};
+use crate::hls::{segment_file_name, Playlist, Segment};
+use crate::metrics::FfmpegRun;
+use crate::playback::{PlaybackAuth, PlaybackSigner};
+use crate::storage::{self, SharedStorage};
+use crate::transcode::TranscodeArgs;
+use crate::utils::{database_error, ResourceDir};
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateClip {
+    /// Seconds from the start of the source stream
+    start: f64,
+    end: f64,
+    name: Option<String>,
+    description: Option<String>,
+    /// Re-encode the clip so it starts and ends exactly on the given timestamps
+    /// instead of on the surrounding segment boundaries
+    #[serde(default)]
+    frame_accurate: bool,
+    /// Defaults to the visibility of the source stream, only admins can make a
+    /// clip more visible than it
+    visibility: Option<Visibility>,
+    /// Defaults to the tags of the source stream
+    tags: Option<Vec<String>>,
+}
+
+/// Clipping a stream takes the same access as watching it.
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip(auth, db, resource_dir, storage, keys, transcode, events, signer))]
+pub async fn create_clip(
+    Path(stream_id): Path<String>,
+    auth: PlaybackAuth,
+    db: State<Pool<Sqlite>>,
+    State(resource_dir): State<ResourceDir>,
+    State(storage): State<SharedStorage>,
+    State(keys): State<Keys>,
+    State(transcode): State<TranscodeArgs>,
+    State(events): State<EventBus>,
+    State(signer): State<PlaybackSigner>,
+    Json(body): Json<CreateClip>,
+) -> Result<Json<Stream>, StatusCode> {
+    if body.start < 0.0 || body.end <= body.start {
+        warn!("Invalid clip range");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let tags = match body.tags {
+        Some(tags) => Some(normalize_tags(tags).ok_or_else(|| {
+            warn!("Invalid tags");
+            StatusCode::BAD_REQUEST
+        })?),
+        None => None,
+    };
+
+    let (parent_name, width, height, parent_visibility, password_hash): (
+        String,
+        i64,
+        i64,
+        Visibility,
+        Option<String>,
+    ) =
+        sqlx::query_as(r#"SELECT `name`, `width`, `height`, `visibility`, `passwordHash` FROM `streams` WHERE `id` = $1 AND `deletedAt` IS NULL"#)
+            .bind(&stream_id)
+            .fetch_optional(&*db)
+            .await
+            .map_err(database_error)?
+            .ok_or_else(|| {
+                warn!("Stream not found");
+                StatusCode::NOT_FOUND
+            })?;
+    let access = StreamAccess {
+        visibility: parent_visibility,
+        password_protected: password_hash.is_some(),
+    };
+    authorize_playback(&signer, &stream_id, access, &auth)?;
+    let visibility = match body.visibility {
+        Some(visibility) if visibility < parent_visibility && !auth.admin => {
+            warn!("Making a clip more visible than its stream requires the admin token");
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        Some(visibility) => visibility,
+        None => parent_visibility,
+    };
+
+    let playlist = Playlist::parse(
+        &read_playlist(&storage, &stream_id)
+            .await
+            .map_err(|(status, _)| status)?,
+    );
+
+    let end = body.end.min(playlist.duration());
+    let (offset, segments) = playlist.segments_between(body.start, end);
+    if segments.is_empty() {
+        warn!("Clip range is outside of the stream");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    // Linked segments cover more than the requested range
+    let (clip_start, clip_end) = if body.frame_accurate {
+        (body.start, end)
+    } else {
+        let duration: f64 = segments.iter().map(|segment| segment.duration).sum();
+        (offset, offset + duration)
+    };
+
+    let clip_id = uuid::Uuid::new_v4().to_string();
+    let parent_dir = resource_dir.stream_dir(&stream_id);
+    let clip_dir = resource_dir.stream_dir(&clip_id);
+    if let Err(err) = tokio::fs::create_dir(&clip_dir).await {
+        error!(%err, "Failed to create resources dir");
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    let result = if body.frame_accurate {
+        cut_segments(
+            &db,
+            &keys,
+            &transcode,
+            &stream_id,
+            &parent_dir,
+            &clip_dir,
+            &clip_id,
+            &playlist,
+            segments,
+            body.start - offset,
+            end - offset,
+        )
+        .await
+    } else {
+        // Linked segments stay encrypted with the source's keys
+        match encryption::copy_keys(&db, &stream_id, &clip_id).await {
+            Ok(()) => link_segments(&parent_dir, &clip_dir, &playlist, segments).await,
+            Err(err) => Err(err.into()),
+        }
+    };
+    let result = match result {
+        Ok(()) => storage::upload_stream(&storage, &resource_dir, &clip_id).await,
+        Err(err) => Err(err),
+    };
+
+    if let Err(err) = result {
+        error!(%err, "Failed to create clip");
+        if !storage.is_local() {
+            if let Err(err) = storage.delete_stream(&clip_id).await {
+                error!(%err, "Failed to delete clip from storage");
+            }
+        }
+        if let Err(err) = tokio::fs::remove_dir_all(&clip_dir).await {
+            error!(%err, "Failed to remove resources dir");
+        }
+        if let Err(err) = encryption::delete_keys(&db, &clip_id).await {
+            error!(%err, "Failed to delete clip keys");
+        }
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    sqlx::query(
+        r#"insert into streams (id, name, description, startTime, endTime, width, height, parentId, clipStart, clipEnd, visibility, passwordHash) values ($1, $2, $3, $4, $4, $5, $6, $7, $8, $9, $10, $11)"#,
+    )
+    .bind(&clip_id)
+    .bind(body.name.unwrap_or_else(|| format!("Clip of {}", parent_name)))
+    .bind(body.description.unwrap_or_default())
+    .bind(chrono::Utc::now())
+    .bind(width)
+    .bind(height)
+    .bind(&stream_id)
+    .bind(clip_start)
+    .bind(clip_end)
+    .bind(visibility)
+    .bind(password_hash)
+    .execute(&*db)
+    .await
+    .map_err(database_error)?;
+
+    let tagged = match &tags {
+        Some(tags) => set_stream_tags(&db, &clip_id, tags).await,
+        None => copy_stream_tags(&db, &stream_id, &clip_id).await,
+    };
+    if let Err(err) = tagged {
+        error!(%err, "Failed to tag clip");
+    }
+
+    info!(clip_id, "Created clip");
+    events.publish(StreamEvent::StreamCreated {
+        stream_id: clip_id.clone(),
+    });
+
+    let mut clip: Stream = sqlx::query_as(r#"SELECT * FROM `streams` WHERE `id` = $1"#)
+        .bind(&clip_id)
+        .fetch_one(&*db)
+        .await
+        .map_err(database_error)?;
+    attach_tags(&db, [&mut clip])
+        .await
+        .map_err(database_error)?;
+
+    Ok(Json(clip))
+}
+
+/// Only public clips are listed unless the request carries the admin token.
+#[instrument(skip(db))]
+pub async fn get_clips(
+    Path(stream_id): Path<String>,
+    IsAdmin(admin): IsAdmin,
+    db: State<Pool<Sqlite>>,
+) -> Result<Json<Vec<Stream>>, StatusCode> {
+    let mut clips: Vec<Stream> = sqlx::query_as(
+        r#"SELECT * FROM `streams` WHERE `parentId` = $1 AND `deletedAt` IS NULL AND ($2 OR `visibility` = $3) ORDER BY `startTime` DESC"#,
+    )
+    .bind(&stream_id)
+    .bind(admin)
+    .bind(Visibility::Public)
+    .fetch_all(&*db)
+    .await
+    .map_err(database_error)?;
+    attach_tags(&db, &mut clips).await.map_err(database_error)?;
+
+    Ok(Json(clips))
+}
+
+/// Builds the clip out of the source segments without re-encoding. The segments
+/// are hard linked so the clip keeps working after the source is deleted.
+async fn link_segments(
+    parent_dir: &FsPath,
+    clip_dir: &FsPath,
+    source: &Playlist,
+    segments: Vec<Segment>,
+) -> Result<(), anyhow::Error> {
+    for segment in &segments {
+        let file_name = segment_file_name(&segment.uri);
+        let from = parent_dir.join(file_name);
+        let to = clip_dir.join(file_name);
+        if let Err(err) = tokio::fs::hard_link(&from, &to).await {
+            warn!(%err, "Failed to link segment, copying it instead");
+            tokio::fs::copy(&from, &to).await?;
+        }
+    }
+
+    let clip = Playlist {
+        header: clip_header(&source.header),
+        segments,
+        ended: true,
+    }
+    .map_uris(|uri| segment_file_name(uri).to_string())
+    .map_key_uris(|uri| segment_file_name(uri).to_string());
+
+    tokio::fs::write(clip_dir.join("index.m3u8"), clip.render()).await?;
+    Ok(())
+}
+
+/// Re-encodes the source segments trimmed to exactly `start..end`, both relative
+/// to the first segment. Clips of encrypted streams are encrypted with a key of
+/// their own.
+#[allow(clippy::too_many_arguments)]
+async fn cut_segments(
+    db: &Pool<Sqlite>,
+    keys: &Keys,
+    transcode: &TranscodeArgs,
+    parent_id: &str,
+    parent_dir: &FsPath,
+    clip_dir: &FsPath,
+    clip_id: &str,
+    source: &Playlist,
+    segments: Vec<Segment>,
+    start: f64,
+    end: f64,
+) -> Result<(), anyhow::Error> {
+    let parent_dir = tokio::fs::canonicalize(parent_dir).await?;
+    let source_playlist = Playlist {
+        header: source.header.clone(),
+        segments,
+        ended: true,
+    }
+    .map_uris(|uri| {
+        parent_dir
+            .join(segment_file_name(uri))
+            .to_string_lossy()
+            .to_string()
+    });
+    let (source_playlist, local_keys) = keys.local_keys(db, parent_id, source_playlist).await?;
+    tokio::fs::write(clip_dir.join("source.m3u8"), source_playlist.render()).await?;
+    let key_rotation = if local_keys.is_empty() {
+        None
+    } else {
+        Some(KeyRotation::start(keys, db, clip_id).await?)
+    };
+
+    let ffmpeg_run = FfmpegRun::start("clip");
+    let status = tokio::process::Command::new("ffmpeg")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .args(["-y", "-ss", &start.to_string(), "-to", &end.to_string()])
+        .args(encryption::ffmpeg_input_args(&local_keys))
+        .args([
+            "-i",
+            "source.m3u8",
+            "-force_key_frames",
+            &transcode.force_key_frames(),
+            "-hls_time",
+            &transcode.hls_time.to_string(),
+            "-hls_list_size",
+            "0",
+            "-hls_playlist_type",
+            "vod",
+            "-hls_flags",
+            "independent_segments",
+            "-hls_segment_filename",
+            "%03d.ts",
+            "-f",
+            "hls",
+            "-c:v",
+            &transcode.video_codec,
+        ])
+        .args(key_rotation.iter().flat_map(KeyRotation::ffmpeg_args))
+        .arg("index.m3u8")
+        .current_dir(clip_dir)
+        .status()
+        .await;
+    if let Some(key_rotation) = key_rotation {
+        key_rotation.finish().await;
+    }
+    drop(local_keys);
+
+    if let Err(err) = tokio::fs::remove_file(clip_dir.join("source.m3u8")).await {
+        warn!(%err, "Failed to remove clip source playlist");
+    }
+
+    let status = status?;
+    if !status.success() {
+        return Err(anyhow!("ffmpeg exited with {}", status));
+    }
+    ffmpeg_run.succeeded();
+    Ok(())
+}
+
+fn clip_header(source: &[String]) -> Vec<String> {
+    let mut header: Vec<String> = source
+        .iter()
+        .filter(|line| !line.starts_with("#EXT-X-PLAYLIST-TYPE"))
+        .map(|line| {
+            if line.starts_with("#EXT-X-MEDIA-SEQUENCE") {
+                "#EXT-X-MEDIA-SEQUENCE:0".to_string()
+            } else {
+                line.clone()
+            }
+        })
+        .collect();
+    header.push("#EXT-X-PLAYLIST-TYPE:VOD".to_string());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, extract::FromRef, http::Request, routing::post, Router};
+    use clap::Parser;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::auth::AdminToken;
+    use crate::encryption::EncryptionArgs;
+    use crate::playback::PlaybackArgs;
+    use crate::storage::StorageArgs;
+    use crate::utils::test_db;
+
+    #[derive(Clone, FromRef)]
+    struct TestState {
+        db: Pool<Sqlite>,
+        resource_dir: ResourceDir,
+        storage: SharedStorage,
+        keys: Keys,
+        transcode: TranscodeArgs,
+        events: EventBus,
+        signer: PlaybackSigner,
+        admin_token: AdminToken,
+    }
+
+    #[derive(Parser)]
+    struct TestCli {
+        #[command(flatten)]
+        storage: StorageArgs,
+        #[command(flatten)]
+        encryption: EncryptionArgs,
+        #[command(flatten)]
+        transcode: TranscodeArgs,
+    }
+
+    /// An app with a stream of a single segment, which is `visibility`.
+    async fn clip_app(visibility: Visibility) -> (Router, Pool<Sqlite>) {
+        let root = std::env::temp_dir().join(format!("clips-{}", uuid::Uuid::new_v4()));
+        let resource_dir = ResourceDir::new(root.clone(), root.join("logs"));
+        let stream_dir = resource_dir.stream_dir("stream");
+        tokio::fs::create_dir_all(&stream_dir).await.unwrap();
+        tokio::fs::write(
+            stream_dir.join("index.m3u8"),
+            "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXTINF:4.0,\n000.ts\n#EXT-X-ENDLIST\n",
+        )
+        .await
+        .unwrap();
+        tokio::fs::write(stream_dir.join("000.ts"), b"ts")
+            .await
+            .unwrap();
+
+        let db = test_db().await;
+        sqlx::query(
+            r#"insert into streams (id, name, description, startTime, width, height, visibility) values ('stream', 'Stream', '', $1, 1920, 1080, $2)"#,
+        )
+        .bind(chrono::Utc::now())
+        .bind(visibility)
+        .execute(&db)
+        .await
+        .unwrap();
+
+        let cli = TestCli::parse_from([
+            "test",
+            "--storage",
+            "local",
+            "--key-dir",
+            root.join("keys").to_str().unwrap(),
+        ]);
+        let app = Router::new()
+            .route("/stream/:streamId/clips", post(create_clip))
+            .with_state(TestState {
+                db: db.clone(),
+                storage: storage::from_args(&cli.storage, &resource_dir).unwrap(),
+                resource_dir,
+                keys: Keys::from_args(&cli.encryption).await.unwrap(),
+                transcode: cli.transcode,
+                events: EventBus::new(16),
+                signer: PlaybackSigner::from_args(&PlaybackArgs {
+                    playback_secret: Some("secret".to_string()),
+                    require_playback_token: false,
+                    playback_token_ttl: 3600,
+                    trust_forwarded_for: false,
+                    forwarded_for_hops: 1,
+                }),
+                admin_token: AdminToken(Some("admin".into())),
+            });
+        (app, db)
+    }
+
+    /// Clips the whole stream, returning the status and the clip's visibility.
+    async fn clip(
+        app: &Router,
+        admin: bool,
+        visibility: Option<&str>,
+    ) -> (StatusCode, Option<Visibility>) {
+        let mut body = serde_json::json!({ "start": 0, "end": 4 });
+        if let Some(visibility) = visibility {
+            body["visibility"] = visibility.into();
+        }
+        let mut req = Request::post("/stream/stream/clips")
+            .header(axum::http::header::CONTENT_TYPE, "application/json");
+        if admin {
+            req = req.header(axum::http::header::AUTHORIZATION, "Bearer admin");
+        }
+        let res = app
+            .clone()
+            .oneshot(req.body(Body::from(body.to_string())).unwrap())
+            .await
+            .unwrap();
+        let status = res.status();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let clip: Option<serde_json::Value> = serde_json::from_slice(&body).ok();
+        let visibility =
+            clip.map(|clip| serde_json::from_value(clip["visibility"].clone()).unwrap());
+        (status, visibility)
+    }
+
+    #[tokio::test]
+    async fn clips_inherit_the_visibility_of_the_stream() {
+        let (app, _) = clip_app(Visibility::Unlisted).await;
+        assert_eq!(
+            clip(&app, false, None).await,
+            (StatusCode::OK, Some(Visibility::Unlisted))
+        );
+    }
+
+    #[tokio::test]
+    async fn clipping_a_private_stream_takes_access_to_it() {
+        let (app, db) = clip_app(Visibility::Private).await;
+        assert_eq!(
+            clip(&app, false, None).await,
+            (StatusCode::UNAUTHORIZED, None)
+        );
+        let clips: i64 = sqlx::query_scalar(r#"SELECT count(*) FROM `streams`"#)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(clips, 1);
+
+        assert_eq!(
+            clip(&app, true, None).await,
+            (StatusCode::OK, Some(Visibility::Private))
+        );
+    }
+
+    #[tokio::test]
+    async fn only_admins_make_clips_more_visible() {
+        let (app, _) = clip_app(Visibility::Unlisted).await;
+        assert_eq!(
+            clip(&app, false, Some("private")).await,
+            (StatusCode::OK, Some(Visibility::Private))
+        );
+        assert_eq!(
+            clip(&app, false, Some("public")).await,
+            (StatusCode::UNAUTHORIZED, None)
+        );
+        assert_eq!(
+            clip(&app, true, Some("public")).await,
+            (StatusCode::OK, Some(Visibility::Public))
+        );
+    }
+}
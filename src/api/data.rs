@@ -1,4 +1,3 @@
-use chrono::serde::ts_seconds;
 use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
 
@@ -6,10 +5,151 @@ use sqlx::prelude::FromRow;
 #[serde(rename_all = "camelCase")]
 #[sqlx(rename_all = "camelCase")]
 pub struct Stream {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub start_time: chrono::DateTime<chrono::Utc>,
+    pub width: i64,
+    pub height: i64,
+    /// The stream this is a clip of
+    pub parent_id: Option<String>,
+    pub clip_start: Option<f64>,
+    pub clip_end: Option<f64>,
+    pub status: StreamStatus,
+    pub end_time: Option<chrono::DateTime<chrono::Utc>>,
+    #[sqlx(skip)]
+    pub tags: Vec<String>,
+    pub peak_viewers: i64,
+    pub total_views: i64,
+    #[sqlx(skip)]
+    pub current_viewers: i64,
+    /// Exempt from retention
+    pub pinned: bool,
+    /// When the stream was moved to the trash
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub visibility: Visibility,
+    /// Only whether there is one is serialized
+    #[serde(
+        rename = "passwordProtected",
+        serialize_with = "serialize_is_some",
+        skip_deserializing
+    )]
+    pub password_hash: Option<String>,
+}
+
+fn serialize_is_some<S: serde::Serializer, T>(
+    value: &Option<T>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_bool(value.is_some())
+}
+
+/// What a stream asks of viewers before they can watch it.
+#[derive(Debug, Clone, Copy, Default, FromRow)]
+#[sqlx(rename_all = "camelCase")]
+pub struct StreamAccess {
+    pub visibility: Visibility,
+    pub password_protected: bool,
+}
+
+/// Who can find and watch a stream, ordered from least to most restrictive.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, sqlx::Type, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum Visibility {
+    /// Listed and playable by anyone
+    #[default]
+    Public,
+    /// Left out of listings but playable by anyone with the link
+    Unlisted,
+    /// Left out of listings and only playable with a playback token or the admin token
+    Private,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum StreamStatus {
+    /// An upload that ffmpeg is still transcoding
+    Processing,
+    Live,
+    Ended,
+    Failed,
+    /// Cut short by the server shutting down, the playlist is finalized up to
+    /// where ingest stopped
+    Interrupted,
+}
+
+impl StreamStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StreamStatus::Processing => "processing",
+            StreamStatus::Live => "live",
+            StreamStatus::Ended => "ended",
+            StreamStatus::Failed => "failed",
+            StreamStatus::Interrupted => "interrupted",
+        }
+    }
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[sqlx(rename_all = "camelCase")]
+pub struct Channel {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub owner: String,
+    pub default_width: Option<i64>,
+    pub default_height: Option<i64>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// A single live session or upload on a channel, together with the stream it
+/// produced.
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[sqlx(rename_all = "camelCase")]
+pub struct Broadcast {
+    broadcast_id: String,
+    channel_id: String,
+    kind: String,
+    started_at: chrono::DateTime<chrono::Utc>,
+    ended_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[sqlx(flatten)]
+    pub stream: Stream,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[sqlx(rename_all = "camelCase")]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    /// Only returned when the webhook is created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[sqlx(skip)]
+    pub secret: Option<String>,
+    /// Comma separated event names, `*` for every event
+    pub events: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+#[sqlx(rename_all = "camelCase")]
+pub struct WebhookDelivery {
     id: String,
-    name: String,
-    description: String,
-    start_time: chrono::DateTime<chrono::Utc>,
-    width: i64,
-    height: i64,
+    webhook_id: String,
+    event: String,
+    payload: String,
+    status: String,
+    attempts: i64,
+    response_status: Option<i64>,
+    error: Option<String>,
+    created_at: chrono::DateTime<chrono::Utc>,
+    updated_at: chrono::DateTime<chrono::Utc>,
 }
@@ -0,0 +1,163 @@
+use std::convert::Infallible;
+
+use axum::{
+    extract::State,
+    response::{
+        sse::{Event, KeepAlive},
+        Sse,
+    },
+};
+use futures::{Stream, StreamExt};
+use sqlx::{Pool, Sqlite};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info, instrument, warn};
+
+use crate::api::data::Visibility;
+use crate::auth::IsAdmin;
+use crate::events::EventBus;
+use crate::shutdown::Shutdown;
+
+/// Streams events as they happen. Only events about public streams are sent
+/// unless the request carries the admin token.
+#[instrument(skip(db, events, shutdown))]
+pub async fn events(
+    IsAdmin(admin): IsAdmin,
+    State(db): State<Pool<Sqlite>>,
+    State(events): State<EventBus>,
+    State(shutdown): State<Shutdown>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    info!("Subscribing to events");
+    let receiver = events.subscribe();
+
+    let stream = futures::stream::unfold(receiver, move |mut receiver| {
+        let db = db.clone();
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => {
+                        if !admin && !is_public(&db, event.stream_id()).await {
+                            continue;
+                        }
+                        let sse_event = match Event::default().event(event.name()).json_data(&event)
+                        {
+                            Ok(sse_event) => sse_event,
+                            Err(err) => {
+                                error!(%err, "Failed to serialize event");
+                                continue;
+                            }
+                        };
+                        return Some((Ok(sse_event), receiver));
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "Event subscriber lagged behind");
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }
+    })
+    // Ends the response so graceful shutdown is not held up by subscribers
+    .take_until(shutdown.cancelled());
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// Streams purged from the database can't be told apart from private ones,
+/// their events are only sent to admins.
+async fn is_public(db: &Pool<Sqlite>, stream_id: &str) -> bool {
+    let visibility: Result<Option<Visibility>, _> =
+        sqlx::query_scalar(r#"SELECT `visibility` FROM `streams` WHERE `id` = $1"#)
+            .bind(stream_id)
+            .fetch_optional(db)
+            .await;
+    match visibility {
+        Ok(visibility) => visibility == Some(Visibility::Public),
+        Err(err) => {
+            error!(%err, "Failed to look up stream visibility");
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, extract::FromRef, http::Request, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::auth::AdminToken;
+    use crate::events::StreamEvent;
+    use crate::utils::test_db;
+
+    #[derive(Clone, FromRef)]
+    struct TestState {
+        db: Pool<Sqlite>,
+        events: EventBus,
+        shutdown: Shutdown,
+        admin_token: AdminToken,
+    }
+
+    /// The events an `/events` subscriber gets for one update of a public and
+    /// one of a private stream.
+    async fn received(authorization: Option<&str>) -> String {
+        let db = test_db().await;
+        for (id, visibility) in [
+            ("public", Visibility::Public),
+            ("private", Visibility::Private),
+        ] {
+            sqlx::query(
+                r#"insert into streams (id, name, description, startTime, width, height, visibility) values ($1, '', '', $2, 1920, 1080, $3)"#,
+            )
+            .bind(id)
+            .bind(chrono::Utc::now())
+            .bind(visibility)
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+        let events = EventBus::new(16);
+        let app = Router::new()
+            .route("/events", get(super::events))
+            .with_state(TestState {
+                db,
+                events: events.clone(),
+                shutdown: Shutdown::default(),
+                admin_token: AdminToken(Some("admin".into())),
+            });
+
+        let mut req = Request::get("/events");
+        if let Some(authorization) = authorization {
+            req = req.header(axum::http::header::AUTHORIZATION, authorization);
+        }
+        // The router holds the last other sender, dropping both ends the stream
+        let res = app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap();
+        for stream_id in ["private", "public"] {
+            events.publish(StreamEvent::Updated {
+                stream_id: stream_id.to_string(),
+            });
+        }
+        drop(events);
+
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn only_public_streams_are_sent_to_everyone() {
+        let body = received(None).await;
+        assert!(body.contains(r#""streamId":"public""#), "{}", body);
+        assert!(!body.contains(r#""streamId":"private""#), "{}", body);
+
+        let body = received(Some("Bearer wrong")).await;
+        assert!(!body.contains(r#""streamId":"private""#), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn admins_get_every_event() {
+        let body = received(Some("Bearer admin")).await;
+        assert!(body.contains(r#""streamId":"public""#), "{}", body);
+        assert!(body.contains(r#""streamId":"private""#), "{}", body);
+    }
+}
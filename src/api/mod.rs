@@ -1,3 +1,11 @@
+pub mod channels;
+pub mod clips;
 pub mod data;
+pub mod events;
+pub mod playback;
+pub mod recording;
+pub mod retention;
 pub mod serve;
+pub mod tags;
 pub mod upload;
+pub mod webhooks;
@@ -0,0 +1,180 @@
+use std::net::IpAddr;
+
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+use tracing::{info, instrument, warn};
+
+use crate::api::data::{StreamAccess, Visibility};
+use crate::auth::{verify_password, Admin};
+use crate::playback::{
+    unlock_cookie_name, PlaybackAuth, PlaybackSigner, MAX_TOKEN_TTL, UNLOCK_TTL,
+};
+use crate::server::PublicUrl;
+use crate::utils::database_error;
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct IssueToken {
+    /// Defaults to `--playback-token-ttl`, at most 30 days
+    ttl_secs: Option<u64>,
+    /// Only accept the token from this address
+    ip: Option<IpAddr>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaybackToken {
+    stream_id: String,
+    token: String,
+    expires_at: chrono::DateTime<chrono::Utc>,
+    /// The playlist url with the token attached
+    url: String,
+}
+
+#[instrument(skip(db, signer, public_url, body))]
+pub async fn issue_token(
+    _admin: Admin,
+    Path(stream_id): Path<String>,
+    db: State<Pool<Sqlite>>,
+    State(signer): State<PlaybackSigner>,
+    State(public_url): State<PublicUrl>,
+    body: Option<Json<IssueToken>>,
+) -> Result<Json<PlaybackToken>, StatusCode> {
+    let Json(body) = body.unwrap_or_default();
+    let exists: Option<String> =
+        sqlx::query_scalar(r#"SELECT `id` FROM `streams` WHERE `id` = $1 AND `deletedAt` IS NULL"#)
+            .bind(&stream_id)
+            .fetch_optional(&*db)
+            .await
+            .map_err(database_error)?;
+    if exists.is_none() {
+        warn!("Stream not found");
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let ttl = body
+        .ttl_secs
+        .map(std::time::Duration::from_secs)
+        .unwrap_or(signer.default_ttl);
+    if ttl > MAX_TOKEN_TTL {
+        warn!(?ttl, "Playback token ttl is too long");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let Some(expires_at) = chrono::Duration::from_std(ttl)
+        .ok()
+        .and_then(|ttl| chrono::Utc::now().checked_add_signed(ttl))
+    else {
+        warn!(?ttl, "Playback token ttl is out of range");
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    let token = signer.issue(&stream_id, expires_at, body.ip);
+
+    info!(?expires_at, ip = ?body.ip, "Issued playback token");
+    Ok(Json(PlaybackToken {
+        url: public_url.url(
+            &format!("stream/{}?token={}", stream_id, token),
+            &format!("stream/{}/token", stream_id),
+        ),
+        stream_id,
+        token,
+        expires_at,
+    }))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Unlock {
+    password: String,
+}
+
+/// Trades the password of a stream for a cookie that lets the browser watch it
+/// for a while.
+#[instrument(skip(db, signer, body))]
+pub async fn unlock(
+    Path(stream_id): Path<String>,
+    db: State<Pool<Sqlite>>,
+    State(signer): State<PlaybackSigner>,
+    Json(body): Json<Unlock>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let password_hash: Option<Option<String>> = sqlx::query_scalar(
+        r#"SELECT `passwordHash` FROM `streams` WHERE `id` = $1 AND `deletedAt` IS NULL"#,
+    )
+    .bind(&stream_id)
+    .fetch_optional(&*db)
+    .await
+    .map_err(database_error)?;
+
+    let Some(password_hash) = password_hash else {
+        warn!("Stream not found");
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let Some(password_hash) = password_hash else {
+        warn!("Stream is not password protected");
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    if !verify_password(password_hash, body.password).await {
+        warn!("Wrong stream password");
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let expires_at = chrono::Utc::now()
+        + chrono::Duration::from_std(UNLOCK_TTL).expect("The unlock ttl should fit");
+    let cookie = format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+        unlock_cookie_name(&stream_id),
+        signer.issue(&stream_id, expires_at, None),
+        UNLOCK_TTL.as_secs()
+    );
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::SET_COOKIE,
+        HeaderValue::from_str(&cookie).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+    );
+
+    info!("Unlocked stream");
+    Ok((StatusCode::NO_CONTENT, headers))
+}
+
+/// Checks whether a request may watch a stream, returning the playback token
+/// it carries so the playlist can pass it on to its segments. Unlock cookies
+/// reach the segments by themselves.
+pub fn authorize_playback(
+    signer: &PlaybackSigner,
+    stream_id: &str,
+    access: StreamAccess,
+    auth: &PlaybackAuth,
+) -> Result<Option<String>, StatusCode> {
+    if let Some(token) = &auth.token {
+        return match signer.verify(stream_id, token, auth.client_ip) {
+            Ok(()) => Ok(Some(token.clone())),
+            Err(err) => {
+                warn!(?err, stream_id, client_ip = ?auth.client_ip, "Playback token rejected");
+                Err(StatusCode::FORBIDDEN)
+            }
+        };
+    }
+    if auth.admin {
+        return Ok(None);
+    }
+    if let Some(cookie) = auth.unlock_cookie(stream_id) {
+        match signer.verify(stream_id, &cookie, auth.client_ip) {
+            Ok(()) => return Ok(None),
+            Err(err) => warn!(?err, stream_id, "Unlock cookie rejected"),
+        }
+    }
+
+    if access.password_protected {
+        warn!(stream_id, "Stream is locked");
+        Err(StatusCode::UNAUTHORIZED)
+    } else if signer.required || access.visibility == Visibility::Private {
+        warn!(stream_id, "Missing playback token");
+        Err(StatusCode::UNAUTHORIZED)
+    } else {
+        Ok(None)
+    }
+}
@@ -0,0 +1,276 @@
+use std::path::Path as FsPath;
+use std::process::Stdio;
+
+use anyhow::anyhow;
+use axum::{
+    body::Body,
+    extract::{Path, Request, State},
+    http::{header, HeaderValue},
+    response::{IntoResponse, Redirect, Response},
+};
+use hyper::StatusCode;
+use sqlx::{Pool, Sqlite};
+use tower::ServiceExt;
+use tower_http::services::ServeFile;
+use tracing::{error, info, instrument, warn};
+
+use crate::api::data::{StreamAccess, Visibility};
+use crate::api::playback::authorize_playback;
+use crate::encryption::{self, Keys};
+use crate::hls::{segment_file_name, Playlist};
+use crate::metrics::FfmpegRun;
+use crate::playback::{PlaybackAuth, PlaybackSigner};
+use crate::storage::{object_key, Served, SharedStorage};
+use crate::utils::{database_error, ResourceDir};
+
+pub const RECORDING_FILE_NAME: &str = "recording.mp4";
+const RECORDING_PLAYLIST_NAME: &str = "recording.m3u8";
+const RECORDING_CONTENT_TYPE: &str = "video/mp4";
+
+/// Remuxes the segments of a finished stream into a single faststart MP4 next to
+/// its playlist, decrypting it on the way when it is encrypted, and copies it
+/// into remote storage.
+#[instrument(skip(db, keys, storage))]
+pub async fn remux_to_mp4(
+    db: &Pool<Sqlite>,
+    keys: &Keys,
+    storage: &SharedStorage,
+    stream_id: &str,
+    stream_dir: &FsPath,
+) -> Result<(), anyhow::Error> {
+    info!("Remuxing stream into mp4");
+    // Playlists written before segment urls were built on request point at the
+    // segment and key routes, ffmpeg needs the files on disk
+    let playlist = tokio::fs::read_to_string(stream_dir.join("index.m3u8")).await?;
+    let playlist = Playlist::parse(&playlist).map_uris(|uri| segment_file_name(uri).to_string());
+    let (playlist, local_keys) = keys.local_keys(db, stream_id, playlist).await?;
+    tokio::fs::write(stream_dir.join(RECORDING_PLAYLIST_NAME), playlist.render()).await?;
+
+    // Write to a temporary file first so a half written recording is never served
+    let tmp_file_name = format!("{}.part", RECORDING_FILE_NAME);
+
+    let ffmpeg_run = FfmpegRun::start("recording");
+    let status = tokio::process::Command::new("ffmpeg")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .arg("-y")
+        .args(encryption::ffmpeg_input_args(&local_keys))
+        .args([
+            "-i",
+            RECORDING_PLAYLIST_NAME,
+            "-c",
+            "copy",
+            "-bsf:a",
+            "aac_adtstoasc",
+            "-movflags",
+            "+faststart",
+            "-f",
+            "mp4",
+            &tmp_file_name,
+        ])
+        .current_dir(stream_dir)
+        .status()
+        .await;
+
+    if let Err(err) = tokio::fs::remove_file(stream_dir.join(RECORDING_PLAYLIST_NAME)).await {
+        warn!(%err, "Failed to remove recording playlist");
+    }
+    drop(local_keys);
+    let status = status?;
+
+    if !status.success() {
+        if let Err(err) = tokio::fs::remove_file(stream_dir.join(&tmp_file_name)).await {
+            warn!(%err, "Failed to remove partial recording");
+        }
+        return Err(anyhow!("ffmpeg exited with {}", status));
+    }
+    ffmpeg_run.succeeded();
+
+    tokio::fs::rename(
+        stream_dir.join(&tmp_file_name),
+        stream_dir.join(RECORDING_FILE_NAME),
+    )
+    .await?;
+
+    if !storage.is_local() {
+        let key = object_key(stream_id, RECORDING_FILE_NAME)
+            .ok_or_else(|| anyhow!("Invalid stream id {:?}", stream_id))?;
+        let body = tokio::fs::read(stream_dir.join(RECORDING_FILE_NAME)).await?;
+        storage.put(&key, body, RECORDING_CONTENT_TYPE).await?;
+    }
+
+    info!("Recorded stream");
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip(auth, db, resource_dir, storage, signer, req))]
+pub async fn download(
+    Path(stream_id): Path<String>,
+    auth: PlaybackAuth,
+    db: State<Pool<Sqlite>>,
+    State(resource_dir): State<ResourceDir>,
+    State(storage): State<SharedStorage>,
+    State(signer): State<PlaybackSigner>,
+    req: Request,
+) -> Result<Response, StatusCode> {
+    let stream: Option<(String, Visibility, bool)> = sqlx::query_as(
+        r#"SELECT `name`, `visibility`, `passwordHash` IS NOT NULL FROM `streams` WHERE `id` = $1 AND `deletedAt` IS NULL"#,
+    )
+    .bind(&stream_id)
+    .fetch_optional(&*db)
+    .await
+    .map_err(database_error)?;
+
+    let Some((name, visibility, password_protected)) = stream else {
+        warn!("Stream not found");
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let access = StreamAccess {
+        visibility,
+        password_protected,
+    };
+    authorize_playback(&signer, &stream_id, access, &auth)?;
+
+    let mut res = if storage.is_local() {
+        let path = resource_dir
+            .stream_dir(&stream_id)
+            .join(RECORDING_FILE_NAME);
+
+        // ServeFile takes care of range requests and conditional headers for us
+        match ServeFile::new(&path).oneshot(req).await {
+            Ok(res) => res.map(Body::new),
+            Err(err) => {
+                error!(%err, ?path, "Failed to serve recording {}", err);
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+    } else {
+        let Some(key) = object_key(&stream_id, RECORDING_FILE_NAME) else {
+            warn!("Invalid stream id");
+            return Err(StatusCode::NOT_FOUND);
+        };
+        match storage.serve(&key).await {
+            Ok(Some(Served::Body(body))) => {
+                ([(header::CONTENT_TYPE, RECORDING_CONTENT_TYPE)], body).into_response()
+            }
+            // The bucket handles range requests itself
+            Ok(Some(Served::Redirect(url))) => return Ok(Redirect::temporary(&url).into_response()),
+            Ok(None) => {
+                warn!(key, "Recording not found");
+                return Err(StatusCode::NOT_FOUND);
+            }
+            Err(err) => {
+                error!(%err, key, "Failed to serve recording");
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+    };
+
+    if res.status().is_success() {
+        let disposition = format!(
+            "attachment; filename=\"{}.mp4\"",
+            download_file_name(&name, &stream_id)
+        );
+        if let Ok(value) = HeaderValue::from_str(&disposition) {
+            res.headers_mut().insert(header::CONTENT_DISPOSITION, value);
+        }
+    }
+
+    Ok(res)
+}
+
+/// Strips the stream name down to characters that are safe in a quoted header
+/// value, falling back to the id when nothing is left.
+fn download_file_name(name: &str, stream_id: &str) -> String {
+    let file_name: String = name
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, ' ' | '-' | '_'))
+        .collect();
+    let file_name = file_name.trim();
+
+    if file_name.is_empty() {
+        stream_id.to_string()
+    } else {
+        file_name.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{extract::FromRef, routing::get, Router};
+
+    use super::*;
+    use crate::auth::AdminToken;
+    use crate::playback::PlaybackArgs;
+    use crate::storage::{MemoryStorage, Storage};
+    use crate::utils::test_db;
+
+    #[derive(Clone, FromRef)]
+    struct TestState {
+        db: Pool<Sqlite>,
+        resource_dir: ResourceDir,
+        storage: SharedStorage,
+        signer: PlaybackSigner,
+        admin_token: AdminToken,
+    }
+
+    #[tokio::test]
+    async fn downloads_come_from_remote_storage() {
+        let db = test_db().await;
+        sqlx::query(
+            r#"insert into streams (id, name, description, startTime, width, height) values ('stream', 'My stream', '', $1, 1920, 1080)"#,
+        )
+        .bind(chrono::Utc::now())
+        .execute(&db)
+        .await
+        .unwrap();
+        let storage = Arc::new(MemoryStorage::default());
+        storage
+            .put(
+                "stream/recording.mp4",
+                b"mp4".to_vec(),
+                RECORDING_CONTENT_TYPE,
+            )
+            .await
+            .unwrap();
+        let root = std::env::temp_dir().join(format!("recording-{}", uuid::Uuid::new_v4()));
+        let app = Router::new()
+            .route("/stream/:streamId/download", get(download))
+            .with_state(TestState {
+                db,
+                resource_dir: ResourceDir::new(root.clone(), root.join("logs")),
+                storage,
+                signer: PlaybackSigner::from_args(&PlaybackArgs {
+                    playback_secret: Some("secret".to_string()),
+                    require_playback_token: false,
+                    playback_token_ttl: 3600,
+                    trust_forwarded_for: false,
+                    forwarded_for_hops: 1,
+                }),
+                admin_token: AdminToken(None),
+            });
+
+        let download = |uri: &'static str| {
+            app.clone()
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        };
+        let res = download("/stream/stream/download").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], RECORDING_CONTENT_TYPE);
+        assert_eq!(
+            res.headers()[header::CONTENT_DISPOSITION],
+            "attachment; filename=\"My stream.mp4\""
+        );
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"mp4");
+
+        let res = download("/stream/other/download").await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,23 @@
+use axum::{extract::State, Json};
+use hyper::StatusCode;
+use sqlx::{Pool, Sqlite};
+use tracing::{error, instrument};
+
+use crate::auth::Admin;
+use crate::retention::{plan, RetentionPolicy, RetentionReport};
+use crate::utils::ResourceDir;
+
+/// What the next retention run would delete, without deleting anything.
+#[instrument(skip(db, resource_dir, policy))]
+pub async fn get_report(
+    _admin: Admin,
+    db: State<Pool<Sqlite>>,
+    State(resource_dir): State<ResourceDir>,
+    State(policy): State<RetentionPolicy>,
+) -> Result<Json<RetentionReport>, StatusCode> {
+    let report = plan(&db, &resource_dir, &policy).await.map_err(|err| {
+        error!(%err, "Failed to plan retention");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(report))
+}
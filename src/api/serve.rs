@@ -1,85 +1,925 @@
-use sqlx::{Pool, Sqlite};
-use std::path::PathBuf;
+use sqlx::{Pool, QueryBuilder, Sqlite};
 
-use crate::api::data::Stream;
-use crate::utils::database_error;
+use crate::api::data::{Stream, StreamAccess, StreamStatus, Visibility};
+use crate::api::playback::authorize_playback;
+use crate::api::tags::{attach_tags, normalize_tags};
+use crate::api::upload::hash_stream_password;
+use crate::auth::{Admin, IsAdmin};
+use crate::encryption;
+use crate::events::{EventBus, StreamEvent};
+use crate::ffmpeg_log;
+use crate::metrics::METRICS;
+use crate::playback::{PlaybackAuth, PlaybackSigner};
+use crate::server::PublicUrl;
+use crate::storage::{object_key, Served, SharedStorage};
+use crate::utils::{database_error, ResourceDir};
+use crate::viewers::{Viewer, ViewerTracker, VIEWER_COOKIE};
 use axum::{
-    body::Body,
-    extract::{Path, State},
-    response::IntoResponse,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Redirect, Response},
     Json,
 };
 use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
 use tracing::{error, info, instrument, warn};
 
-#[instrument()]
-pub async fn stream(Path(stream_id): Path<String>) -> impl IntoResponse {
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip(headers, auth, db, storage, viewers, signer, public_url))]
+pub async fn stream(
+    Path(stream_id): Path<String>,
+    headers: HeaderMap,
+    auth: PlaybackAuth,
+    db: State<Pool<Sqlite>>,
+    State(storage): State<SharedStorage>,
+    State(viewers): State<ViewerTracker>,
+    State(signer): State<PlaybackSigner>,
+    State(public_url): State<PublicUrl>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
     info!("Serving stream");
-    let path = format!("resources/{}/index.m3u8a", stream_id);
-    match tokio::fs::read_to_string(PathBuf::from(&path)).await {
-        Ok(file) => Ok(file),
-        Err(_) => {
-            let path = format!("resources/{}/index.m3u8", stream_id);
-            return match tokio::fs::read_to_string(PathBuf::from(&path)).await {
-                Ok(file) => Ok(file),
-                Err(err) => {
-                    error!(?err, path, "Failed to open m3u8 file {}", err);
-                    Err((StatusCode::NOT_FOUND, format!("File not found: {}", err)))
-                }
-            };
+    let Some(access) = playback_access(&db, &stream_id)
+        .await
+        .map_err(|err| (database_error(err), String::new()))?
+    else {
+        warn!("Stream not found");
+        return Err((StatusCode::NOT_FOUND, "Stream not found".to_string()));
+    };
+    let token = authorize_playback(&signer, &stream_id, access, &auth)
+        .map_err(|status| (status, String::new()))?;
+    let playlist = public_url.playlist(
+        &read_playlist(&storage, &stream_id).await?,
+        &format!("stream/{}", stream_id),
+        &stream_id,
+        token.as_deref(),
+    );
+
+    let mut response_headers = HeaderMap::new();
+    let mut viewer = Viewer::from_request(&headers, auth.client_ip);
+    if viewer.session.is_none() {
+        let session = uuid::Uuid::new_v4().to_string();
+        let cookie = format!(
+            "{}={}; Path=/; Max-Age=86400; HttpOnly; SameSite=Lax",
+            VIEWER_COOKIE, session
+        );
+        if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+            response_headers.insert(header::SET_COOKIE, cookie);
+        }
+        // Without an address this request can only be told apart by the new cookie
+        if viewer.address.is_none() {
+            viewer.session = Some(session);
         }
     }
+    viewers.touch(&db, &stream_id, &viewer).await;
+
+    Ok((response_headers, playlist))
+}
+
+/// Who may watch the stream, `None` when there is no such stream or it has
+/// been moved to the trash.
+pub async fn playback_access(
+    db: &Pool<Sqlite>,
+    stream_id: &str,
+) -> Result<Option<StreamAccess>, sqlx::Error> {
+    let row: Option<(Option<chrono::DateTime<chrono::Utc>>, Visibility, bool)> = sqlx::query_as(
+        r#"SELECT `deletedAt`, `visibility`, `passwordHash` IS NOT NULL FROM `streams` WHERE `id` = $1"#,
+    )
+    .bind(stream_id)
+    .fetch_optional(db)
+    .await?;
+    Ok(match row {
+        None | Some((Some(_), _, _)) => None,
+        Some((None, visibility, password_protected)) => Some(StreamAccess {
+            visibility,
+            password_protected,
+        }),
+    })
 }
 
-#[instrument()]
+pub async fn read_playlist(
+    storage: &SharedStorage,
+    stream_id: &str,
+) -> Result<String, (StatusCode, String)> {
+    let Some(key) = object_key(stream_id, "index.m3u8") else {
+        warn!(stream_id, "Invalid stream id");
+        return Err((StatusCode::NOT_FOUND, "Stream not found".to_string()));
+    };
+    match storage.read(&key).await {
+        Ok(Some(playlist)) => String::from_utf8(playlist).map_err(|err| {
+            error!(%err, key, "Playlist is not utf-8");
+            (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+        }),
+        Ok(None) => {
+            warn!(key, "Playlist not found");
+            Err((StatusCode::NOT_FOUND, "Stream not found".to_string()))
+        }
+        Err(err) => {
+            error!(%err, key, "Failed to read playlist");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, String::new()))
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip(headers, auth, db, storage, viewers, signer))]
 pub async fn serve_segemnt(
     Path((stream_id, segment_id)): Path<(String, String)>,
-) -> impl IntoResponse {
+    headers: HeaderMap,
+    auth: PlaybackAuth,
+    db: State<Pool<Sqlite>>,
+    State(storage): State<SharedStorage>,
+    State(viewers): State<ViewerTracker>,
+    State(signer): State<PlaybackSigner>,
+) -> Result<Response, StatusCode> {
     info!("Serving segemnt");
-    let path = format!("resources/{}/{}", stream_id, segment_id);
+    let _timer = METRICS.segment_serve_duration.start_timer();
+    let Some(access) = playback_access(&db, &stream_id)
+        .await
+        .map_err(database_error)?
+    else {
+        warn!("Stream not found");
+        return Err(StatusCode::NOT_FOUND);
+    };
+    authorize_playback(&signer, &stream_id, access, &auth)?;
+    let Some(key) = object_key(&stream_id, &segment_id) else {
+        warn!("Invalid segment path");
+        return Err(StatusCode::NOT_FOUND);
+    };
 
-    match tokio::fs::File::open(PathBuf::from(&path)).await {
-        Ok(file) => Ok(Body::from_stream(tokio_util::io::ReaderStream::new(file))),
-        Err(err) => {
-            error!(?err, path, "Failed to open stream segemnt {}", err);
+    let served = match storage.serve(&key).await {
+        Ok(Some(served)) => served,
+        Ok(None) => {
+            warn!(key, "Segment not found");
             return Err(StatusCode::NOT_FOUND);
         }
+        Err(err) => {
+            error!(%err, key, "Failed to serve stream segemnt");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    viewers
+        .touch(
+            &db,
+            &stream_id,
+            &Viewer::from_request(&headers, auth.client_ip),
+        )
+        .await;
+    Ok(match served {
+        Served::Body(body) => body.into_response(),
+        Served::Redirect(url) => Redirect::temporary(&url).into_response(),
+    })
+}
+
+/// Hands a player the key an encrypted stream's segments were encrypted with,
+/// under the same rules as the segments themselves. This is deliberate, anyone
+/// who may fetch the segments may decrypt them, so keys don't need a token of
+/// their own. Encryption keeps segments that leak out of storage or caches from
+/// being played, it doesn't restrict who can watch.
+#[instrument(skip(auth, db, signer))]
+pub async fn serve_key(
+    Path((stream_id, key_id)): Path<(String, String)>,
+    auth: PlaybackAuth,
+    db: State<Pool<Sqlite>>,
+    State(signer): State<PlaybackSigner>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let Some(access) = playback_access(&db, &stream_id)
+        .await
+        .map_err(database_error)?
+    else {
+        warn!("Stream not found");
+        return Err(StatusCode::NOT_FOUND);
+    };
+    authorize_playback(&signer, &stream_id, access, &auth)?;
+
+    let Some(key) = encryption::get_key(&db, &stream_id, &key_id)
+        .await
+        .map_err(database_error)?
+    else {
+        warn!("Key not found");
+        return Err(StatusCode::NOT_FOUND);
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/octet-stream"),
+    );
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("private, no-store"),
+    );
+    Ok((headers, key))
+}
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+pub const NEXT_CURSOR_HEADER: &str = "x-next-cursor";
+
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum StreamSort {
+    #[default]
+    Newest,
+    Oldest,
+    Name,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StreamsQuery {
+    /// The `x-next-cursor` header of the previous page
+    cursor: Option<String>,
+    limit: Option<i64>,
+    from: Option<chrono::DateTime<chrono::Utc>>,
+    to: Option<chrono::DateTime<chrono::Utc>>,
+    status: Option<StreamStatus>,
+    /// The owner of the channel the stream was broadcast on
+    owner: Option<String>,
+    /// `{width}x{height}`
+    resolution: Option<String>,
+    /// Comma separated tags the stream must all have
+    tag: Option<String>,
+    #[serde(default)]
+    sort: StreamSort,
+    /// Full text search over the name and description
+    q: Option<String>,
+    /// Only honoured with the admin token, everyone else only sees public streams
+    visibility: Option<Visibility>,
+}
+
+/// The sort key and id of the last stream on a page, hex encoded so clients
+/// treat it as opaque.
+#[derive(Serialize, Deserialize, Debug)]
+struct Cursor {
+    key: String,
+    id: String,
+}
+
+impl Cursor {
+    fn after(stream: &Stream, sort: StreamSort) -> Cursor {
+        let key = match sort {
+            StreamSort::Newest | StreamSort::Oldest => stream.start_time.to_rfc3339(),
+            StreamSort::Name => stream.name.clone(),
+        };
+        Cursor {
+            key,
+            id: stream.id.clone(),
+        }
+    }
+
+    fn encode(&self) -> String {
+        hex::encode(serde_json::to_vec(self).expect("A cursor should serialize"))
+    }
+
+    fn decode(cursor: &str) -> Option<Cursor> {
+        serde_json::from_slice(&hex::decode(cursor).ok()?).ok()
     }
 }
 
-#[instrument(skip(db))]
-pub async fn get_streams(db: State<Pool<Sqlite>>) -> Result<Json<Vec<Stream>>, StatusCode> {
-    let streams = sqlx::query_as(r#"SELECT * FROM `streams` ORDER BY `startTime` DESC"#)
+#[instrument(skip(db, viewers))]
+pub async fn get_streams(
+    Query(params): Query<StreamsQuery>,
+    IsAdmin(admin): IsAdmin,
+    db: State<Pool<Sqlite>>,
+    State(viewers): State<ViewerTracker>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    let mut query =
+        QueryBuilder::<Sqlite>::new("SELECT s.* FROM `streams` s WHERE s.`deletedAt` IS NULL");
+    match (admin, params.visibility) {
+        (true, Some(visibility)) => {
+            query.push(" AND s.`visibility` = ").push_bind(visibility);
+        }
+        (true, None) => (),
+        (false, _) => {
+            query
+                .push(" AND s.`visibility` = ")
+                .push_bind(Visibility::Public);
+        }
+    }
+
+    if let Some(from) = params.from {
+        query.push(" AND s.`startTime` >= ").push_bind(from);
+    }
+    if let Some(to) = params.to {
+        query.push(" AND s.`startTime` <= ").push_bind(to);
+    }
+    if let Some(status) = params.status {
+        query.push(" AND s.`status` = ").push_bind(status);
+    }
+    if let Some(owner) = params.owner {
+        query
+            .push(
+                " AND s.`id` IN (SELECT b.`streamId` FROM `broadcasts` b JOIN `channels` c ON c.`id` = b.`channelId` WHERE c.`owner` = ",
+            )
+            .push_bind(owner)
+            .push(")");
+    }
+    if let Some(resolution) = params.resolution {
+        let Some((width, height)) = parse_resolution(&resolution) else {
+            warn!(resolution, "Invalid resolution filter");
+            return Err(StatusCode::BAD_REQUEST);
+        };
+        query
+            .push(" AND s.`width` = ")
+            .push_bind(width)
+            .push(" AND s.`height` = ")
+            .push_bind(height);
+    }
+    if let Some(tags) = params.tag {
+        let Some(tags) = normalize_tags(tags.split(',').map(str::to_string)) else {
+            warn!(tags, "Invalid tag filter");
+            return Err(StatusCode::BAD_REQUEST);
+        };
+        for tag in tags {
+            query
+                .push(
+                    " AND s.`id` IN (SELECT st.`streamId` FROM `streamTags` st JOIN `tags` t ON t.`id` = st.`tagId` WHERE t.`name` = ",
+                )
+                .push_bind(tag)
+                .push(")");
+        }
+    }
+    if let Some(search) = params.q.as_deref().and_then(fts_query) {
+        query
+            .push(" AND s.rowid IN (SELECT rowid FROM `streams_fts` WHERE `streams_fts` MATCH ")
+            .push_bind(search)
+            .push(")");
+    }
+
+    if let Some(cursor) = params.cursor.filter(|cursor| !cursor.is_empty()) {
+        let Some(cursor) = Cursor::decode(&cursor) else {
+            warn!(cursor, "Invalid cursor");
+            return Err(StatusCode::BAD_REQUEST);
+        };
+        match params.sort {
+            StreamSort::Newest | StreamSort::Oldest => {
+                let Ok(start_time) = chrono::DateTime::parse_from_rfc3339(&cursor.key) else {
+                    warn!(cursor.key, "Invalid cursor start time");
+                    return Err(StatusCode::BAD_REQUEST);
+                };
+                let operator = match params.sort {
+                    StreamSort::Newest => "<",
+                    _ => ">",
+                };
+                query
+                    .push(format!(" AND (s.`startTime`, s.`id`) {} (", operator))
+                    .push_bind(start_time.with_timezone(&chrono::Utc))
+                    .push(", ")
+                    .push_bind(cursor.id)
+                    .push(")");
+            }
+            StreamSort::Name => {
+                query
+                    .push(" AND (s.`name`, s.`id`) > (")
+                    .push_bind(cursor.key)
+                    .push(", ")
+                    .push_bind(cursor.id)
+                    .push(")");
+            }
+        }
+    }
+
+    query.push(match params.sort {
+        StreamSort::Newest => " ORDER BY s.`startTime` DESC, s.`id` DESC",
+        StreamSort::Oldest => " ORDER BY s.`startTime` ASC, s.`id` ASC",
+        StreamSort::Name => " ORDER BY s.`name` ASC, s.`id` ASC",
+    });
+    // Fetch one extra row to know whether there is another page
+    query.push(" LIMIT ").push_bind(limit + 1);
+
+    let mut streams: Vec<Stream> = query
+        .build_query_as()
         .fetch_all(&*db)
         .await
         .map_err(database_error)?;
 
-    Ok(Json(streams))
+    let mut headers = HeaderMap::new();
+    if streams.len() as i64 > limit {
+        streams.truncate(limit as usize);
+        if let Some(last) = streams.last() {
+            let cursor = Cursor::after(last, params.sort).encode();
+            if let Ok(value) = HeaderValue::from_str(&cursor) {
+                headers.insert(NEXT_CURSOR_HEADER, value);
+            }
+        }
+    }
+
+    attach_tags(&db, &mut streams)
+        .await
+        .map_err(database_error)?;
+    for stream in streams.iter_mut() {
+        stream.current_viewers = viewers.current(&stream.id);
+    }
+
+    Ok((headers, Json(streams)))
 }
 
-#[instrument(skip(db))]
-pub async fn delete_stream(
+fn parse_resolution(resolution: &str) -> Option<(i64, i64)> {
+    let (width, height) = resolution.split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
+
+/// Turns free text into an FTS5 query that prefix matches every term, quoting
+/// them so user input can never be a syntax error.
+fn fts_query(search: &str) -> Option<String> {
+    let terms: Vec<String> = search
+        .split_whitespace()
+        .map(|term| format!("\"{}\"*", term.replace('"', "\"\"")))
+        .collect();
+
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamStats {
+    stream_id: String,
+    current_viewers: i64,
+    peak_viewers: i64,
+    total_views: i64,
+}
+
+#[instrument(skip(db, viewers))]
+pub async fn get_stream_stats(
     Path(stream_id): Path<String>,
     db: State<Pool<Sqlite>>,
-) -> Result<(), StatusCode> {
-    info!("Deleting stream");
-    let deleted = sqlx::query(r#"DELETE FROM `streams` where id = $1"#)
-        .bind(stream_id.clone())
-        .execute(&*db)
+    State(viewers): State<ViewerTracker>,
+) -> Result<Json<StreamStats>, StatusCode> {
+    let (peak_viewers, total_views): (i64, i64) =
+        sqlx::query_as(r#"SELECT `peakViewers`, `totalViews` FROM `streams` WHERE `id` = $1 AND `deletedAt` IS NULL"#)
+            .bind(&stream_id)
+            .fetch_optional(&*db)
+            .await
+            .map_err(database_error)?
+            .ok_or_else(|| {
+                warn!("Stream not found");
+                StatusCode::NOT_FOUND
+            })?;
+
+    Ok(Json(StreamStats {
+        current_viewers: viewers.current(&stream_id),
+        stream_id,
+        peak_viewers,
+        total_views,
+    }))
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamLogs {
+    stream_id: String,
+    status: StreamStatus,
+    /// The last lines ffmpeg wrote before a failed transcode
+    failure_reason: Option<String>,
+    log: String,
+}
+
+#[instrument(skip(db, resource_dir))]
+pub async fn get_stream_logs(
+    _admin: Admin,
+    Path(stream_id): Path<String>,
+    db: State<Pool<Sqlite>>,
+    State(resource_dir): State<ResourceDir>,
+) -> Result<Json<StreamLogs>, StatusCode> {
+    let (status, failure_reason): (StreamStatus, Option<String>) =
+        sqlx::query_as(r#"SELECT `status`, `failureReason` FROM `streams` WHERE `id` = $1"#)
+            .bind(&stream_id)
+            .fetch_optional(&*db)
+            .await
+            .map_err(database_error)?
+            .ok_or_else(|| {
+                warn!("Stream not found");
+                StatusCode::NOT_FOUND
+            })?;
+
+    Ok(Json(StreamLogs {
+        log: ffmpeg_log::read(&resource_dir.log_path(&stream_id)).await,
+        stream_id,
+        status,
+        failure_reason,
+    }))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UpdateStream {
+    name: Option<String>,
+    description: Option<String>,
+    pinned: Option<bool>,
+    visibility: Option<Visibility>,
+    /// An empty password removes it
+    password: Option<String>,
+}
+
+/// Anyone can rename and pin streams, changing who can watch them or the
+/// password takes the admin token.
+#[instrument(skip(db, events, body))]
+pub async fn update_stream(
+    Path(stream_id): Path<String>,
+    IsAdmin(admin): IsAdmin,
+    db: State<Pool<Sqlite>>,
+    State(events): State<EventBus>,
+    Json(body): Json<UpdateStream>,
+) -> Result<Json<Stream>, StatusCode> {
+    if body.visibility.is_some() && !admin {
+        warn!("Changing the visibility requires the admin token");
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    if body.password.is_some() && !admin {
+        warn!("Changing the password requires the admin token");
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    let set_password = body.password.is_some();
+    let password_hash = hash_stream_password(body.password).await?;
+    let updated = sqlx::query(
+        r#"UPDATE `streams` SET `name` = coalesce($1, `name`), `description` = coalesce($2, `description`), `pinned` = coalesce($3, `pinned`), `visibility` = coalesce($4, `visibility`), `passwordHash` = CASE WHEN $5 THEN $6 ELSE `passwordHash` END WHERE `id` = $7 AND `deletedAt` IS NULL"#,
+    )
+    .bind(body.name)
+    .bind(body.description)
+    .bind(body.pinned)
+    .bind(body.visibility)
+    .bind(set_password)
+    .bind(password_hash)
+    .bind(&stream_id)
+    .execute(&*db)
+    .await
+    .map_err(database_error)?;
+
+    if updated.rows_affected() == 0 {
+        warn!("Stream not found");
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    info!("Updated stream");
+    events.publish(StreamEvent::Updated {
+        stream_id: stream_id.clone(),
+    });
+
+    let mut stream: Stream = sqlx::query_as(r#"SELECT * FROM `streams` WHERE `id` = $1"#)
+        .bind(&stream_id)
+        .fetch_one(&*db)
+        .await
+        .map_err(database_error)?;
+    attach_tags(&db, std::slice::from_mut(&mut stream))
         .await
         .map_err(database_error)?;
 
+    Ok(Json(stream))
+}
+
+#[instrument(skip(db, events))]
+pub async fn delete_stream(
+    Path(stream_id): Path<String>,
+    db: State<Pool<Sqlite>>,
+    State(events): State<EventBus>,
+) -> Result<(), StatusCode> {
+    info!("Moving stream to the trash");
+    let deleted = sqlx::query(
+        r#"UPDATE `streams` SET `deletedAt` = $1 WHERE `id` = $2 AND `deletedAt` IS NULL"#,
+    )
+    .bind(chrono::Utc::now())
+    .bind(&stream_id)
+    .execute(&*db)
+    .await
+    .map_err(database_error)?;
+
     if deleted.rows_affected() == 0 {
         warn!("Stream not found");
         return Err(StatusCode::NOT_FOUND);
     }
+    events.publish(StreamEvent::Deleted { stream_id });
+    Ok(())
+}
+
+#[instrument(skip(db, events))]
+pub async fn restore_stream(
+    Path(stream_id): Path<String>,
+    db: State<Pool<Sqlite>>,
+    State(events): State<EventBus>,
+) -> Result<Json<Stream>, StatusCode> {
+    let restored = sqlx::query(
+        r#"UPDATE `streams` SET `deletedAt` = NULL WHERE `id` = $1 AND `deletedAt` IS NOT NULL"#,
+    )
+    .bind(&stream_id)
+    .execute(&*db)
+    .await
+    .map_err(database_error)?;
 
-    let path = format!("resources/{}", stream_id);
-    if let Err(err) = tokio::fs::remove_dir_all(PathBuf::from(&path)).await {
-        error!(?err, path, "Delete stream {}", err);
+    if restored.rows_affected() == 0 {
+        warn!("Stream not found in the trash");
         return Err(StatusCode::NOT_FOUND);
     }
 
-    info!("Deleted stream");
-    Ok(())
+    info!("Restored stream");
+    events.publish(StreamEvent::Restored {
+        stream_id: stream_id.clone(),
+    });
+
+    let mut stream: Stream = sqlx::query_as(r#"SELECT * FROM `streams` WHERE `id` = $1"#)
+        .bind(&stream_id)
+        .fetch_one(&*db)
+        .await
+        .map_err(database_error)?;
+    attach_tags(&db, std::slice::from_mut(&mut stream))
+        .await
+        .map_err(database_error)?;
+
+    Ok(Json(stream))
+}
+
+/// Streams in the trash, most recently deleted first. Only public ones are
+/// listed unless the request carries the admin token.
+#[instrument(skip(db))]
+pub async fn get_trash(
+    IsAdmin(admin): IsAdmin,
+    db: State<Pool<Sqlite>>,
+) -> Result<Json<Vec<Stream>>, StatusCode> {
+    let mut streams: Vec<Stream> = sqlx::query_as(
+        r#"SELECT * FROM `streams` WHERE `deletedAt` IS NOT NULL AND ($1 OR `visibility` = $2) ORDER BY `deletedAt` DESC"#,
+    )
+    .bind(admin)
+    .bind(Visibility::Public)
+    .fetch_all(&*db)
+    .await
+    .map_err(database_error)?;
+    attach_tags(&db, &mut streams)
+        .await
+        .map_err(database_error)?;
+
+    Ok(Json(streams))
+}
+
+/// Permanently deletes a stream's row, files and logs, returning whether it
+/// existed. Every purge goes through here so nothing is left behind.
+pub async fn remove_stream(
+    db: &Pool<Sqlite>,
+    resource_dir: &ResourceDir,
+    storage: &SharedStorage,
+    events: &EventBus,
+    stream_id: &str,
+) -> Result<bool, sqlx::Error> {
+    let deleted: Option<Option<chrono::DateTime<chrono::Utc>>> =
+        sqlx::query_scalar(r#"DELETE FROM `streams` where id = $1 RETURNING `deletedAt`"#)
+            .bind(stream_id)
+            .fetch_optional(db)
+            .await?;
+
+    let Some(deleted_at) = deleted else {
+        return Ok(false);
+    };
+    // Streams in the trash already announced their deletion
+    if deleted_at.is_none() {
+        events.publish(StreamEvent::Deleted {
+            stream_id: stream_id.to_string(),
+        });
+    }
+    METRICS.forget_stream(stream_id);
+    ffmpeg_log::remove(&resource_dir.log_path(stream_id)).await;
+    if let Err(err) = encryption::delete_keys(db, stream_id).await {
+        error!(%err, stream_id, "Failed to delete stream keys");
+    }
+
+    if let Err(err) = storage.delete_stream(stream_id).await {
+        error!(%err, stream_id, "Failed to delete stream from storage");
+    }
+    // Remote storage leaves the copy ffmpeg wrote behind
+    if !storage.is_local() {
+        let path = resource_dir.stream_dir(stream_id);
+        match tokio::fs::remove_dir_all(&path).await {
+            Ok(()) => (),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+            Err(err) => error!(?err, ?path, "Delete stream {}", err),
+        }
+    }
+
+    info!(stream_id, "Deleted stream");
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        extract::FromRef,
+        http::Request,
+        routing::{get, patch, post},
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::auth::AdminToken;
+    use crate::utils::test_db;
+
+    #[derive(Clone, FromRef)]
+    struct TestState {
+        db: Pool<Sqlite>,
+        events: EventBus,
+        admin_token: AdminToken,
+    }
+
+    async fn stream_app() -> (Router, Pool<Sqlite>) {
+        let db = test_db().await;
+        sqlx::query(
+            r#"insert into streams (id, name, description, startTime, width, height) values ('stream', 'Stream', '', $1, 1920, 1080)"#,
+        )
+        .bind(chrono::Utc::now())
+        .execute(&db)
+        .await
+        .unwrap();
+        let app = Router::new()
+            .route(
+                "/stream/:streamId",
+                patch(update_stream).delete(delete_stream),
+            )
+            .route("/stream/:streamId/restore", post(restore_stream))
+            .route("/trash", get(get_trash))
+            .with_state(TestState {
+                db: db.clone(),
+                events: EventBus::new(16),
+                admin_token: AdminToken(Some("admin".into())),
+            });
+        (app, db)
+    }
+
+    async fn update(app: &Router, token: Option<&str>, body: &str) -> StatusCode {
+        let mut req =
+            Request::patch("/stream/stream").header(header::CONTENT_TYPE, "application/json");
+        if let Some(token) = token {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let res = app
+            .clone()
+            .oneshot(req.body(Body::from(body.to_string())).unwrap())
+            .await
+            .unwrap();
+        res.status()
+    }
+
+    async fn visibility(db: &Pool<Sqlite>) -> Visibility {
+        sqlx::query_scalar(r#"SELECT `visibility` FROM `streams` WHERE `id` = 'stream'"#)
+            .fetch_one(db)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn anyone_can_rename_a_stream() {
+        let (app, db) = stream_app().await;
+        assert_eq!(
+            update(&app, None, r#"{"name":"Renamed"}"#).await,
+            StatusCode::OK
+        );
+        let name: String =
+            sqlx::query_scalar(r#"SELECT `name` FROM `streams` WHERE `id` = 'stream'"#)
+                .fetch_one(&db)
+                .await
+                .unwrap();
+        assert_eq!(name, "Renamed");
+    }
+
+    #[tokio::test]
+    async fn changing_the_visibility_takes_the_admin_token() {
+        let (app, db) = stream_app().await;
+        let body = r#"{"name":"Renamed","visibility":"private"}"#;
+        assert_eq!(update(&app, None, body).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            update(&app, Some("wrong"), body).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(visibility(&db).await, Visibility::Public);
+
+        assert_eq!(update(&app, Some("admin"), body).await, StatusCode::OK);
+        assert_eq!(visibility(&db).await, Visibility::Private);
+    }
+
+    #[tokio::test]
+    async fn changing_the_password_takes_the_admin_token() {
+        let (app, db) = stream_app().await;
+        let password_hash = || async {
+            sqlx::query_scalar::<_, Option<String>>(
+                r#"SELECT `passwordHash` FROM `streams` WHERE `id` = 'stream'"#,
+            )
+            .fetch_one(&db)
+            .await
+            .unwrap()
+        };
+        assert_eq!(
+            update(&app, None, r#"{"password":"hunter2"}"#).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(password_hash().await, None);
+
+        assert_eq!(
+            update(&app, Some("admin"), r#"{"password":"hunter2"}"#).await,
+            StatusCode::OK
+        );
+        assert!(password_hash().await.is_some());
+
+        // An empty password removes it, which is just as much of a change
+        assert_eq!(
+            update(&app, None, r#"{"password":""}"#).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert!(password_hash().await.is_some());
+        assert_eq!(
+            update(&app, Some("admin"), r#"{"password":""}"#).await,
+            StatusCode::OK
+        );
+        assert_eq!(password_hash().await, None);
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let cursor = Cursor {
+            key: "2024-01-01T00:00:00+00:00".to_string(),
+            id: "abc".to_string(),
+        };
+        let decoded = Cursor::decode(&cursor.encode()).unwrap();
+        assert_eq!(decoded.key, cursor.key);
+        assert_eq!(decoded.id, cursor.id);
+    }
+
+    #[test]
+    fn cursor_decode_rejects_garbage() {
+        assert!(Cursor::decode("not hex").is_none());
+        assert!(Cursor::decode(&hex::encode("{}")).is_none());
+        assert!(Cursor::decode("").is_none());
+    }
+
+    #[test]
+    fn parse_resolution_reads_width_by_height() {
+        assert_eq!(parse_resolution("1920x1080"), Some((1920, 1080)));
+        assert_eq!(parse_resolution("1920"), None);
+        assert_eq!(parse_resolution("wide x tall"), None);
+    }
+
+    #[test]
+    fn fts_query_quotes_and_prefix_matches_terms() {
+        assert_eq!(
+            fts_query("  cat  vid\"eo ").as_deref(),
+            Some("\"cat\"* \"vid\"\"eo\"*")
+        );
+        assert_eq!(fts_query("   "), None);
+    }
+
+    async fn send(app: &Router, req: axum::http::request::Builder) -> StatusCode {
+        let res = app
+            .clone()
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        res.status()
+    }
+
+    async fn trash(app: &Router) -> Vec<Stream> {
+        let res = app
+            .clone()
+            .oneshot(Request::get("/trash").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn deleted_streams_can_be_restored_from_the_trash() {
+        let (app, _) = stream_app().await;
+        assert_eq!(
+            send(&app, Request::delete("/stream/stream")).await,
+            StatusCode::OK
+        );
+        assert_eq!(trash(&app).await.len(), 1);
+        // Streams in the trash are gone for everything else
+        assert_eq!(
+            send(&app, Request::delete("/stream/stream")).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            update(&app, None, r#"{"name":"Renamed"}"#).await,
+            StatusCode::NOT_FOUND
+        );
+
+        assert_eq!(
+            send(&app, Request::post("/stream/stream/restore")).await,
+            StatusCode::OK
+        );
+        assert!(trash(&app).await.is_empty());
+        assert_eq!(
+            send(&app, Request::post("/stream/stream/restore")).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            update(&app, None, r#"{"name":"Renamed"}"#).await,
+            StatusCode::OK
+        );
+    }
 }
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use hyper::StatusCode;
+use serde::Serialize;
+use sqlx::{prelude::FromRow, Pool, QueryBuilder, Sqlite};
+use tracing::{info, instrument, warn};
+
+use crate::api::data::{Stream, Visibility};
+use crate::events::{EventBus, StreamEvent};
+use crate::utils::database_error;
+
+const MAX_TAG_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, FromRow, Serialize)]
+#[serde(rename_all = "camelCase")]
+#[sqlx(rename_all = "camelCase")]
+pub struct Tag {
+    name: String,
+    stream_count: i64,
+}
+
+#[instrument(skip(db))]
+pub async fn get_tags(db: State<Pool<Sqlite>>) -> Result<Json<Vec<Tag>>, StatusCode> {
+    let tags = sqlx::query_as(
+        r#"SELECT t.`name`, count(s.`id`) AS `streamCount`
+        FROM `tags` t LEFT JOIN `streamTags` st ON st.`tagId` = t.`id`
+        LEFT JOIN `streams` s ON s.`id` = st.`streamId` AND s.`deletedAt` IS NULL AND s.`visibility` = $1
+        GROUP BY t.`id` ORDER BY t.`name`"#,
+    )
+    .bind(Visibility::Public)
+    .fetch_all(&*db)
+    .await
+    .map_err(database_error)?;
+
+    Ok(Json(tags))
+}
+
+/// Replaces every tag on a stream.
+#[instrument(skip(db, events))]
+pub async fn put_stream_tags(
+    Path(stream_id): Path<String>,
+    db: State<Pool<Sqlite>>,
+    State(events): State<EventBus>,
+    Json(names): Json<Vec<String>>,
+) -> Result<Json<Vec<String>>, StatusCode> {
+    let Some(names) = normalize_tags(names) else {
+        warn!("Invalid tags");
+        return Err(StatusCode::BAD_REQUEST);
+    };
+
+    let exists: Option<String> =
+        sqlx::query_scalar(r#"SELECT `id` FROM `streams` WHERE `id` = $1 AND `deletedAt` IS NULL"#)
+            .bind(&stream_id)
+            .fetch_optional(&*db)
+            .await
+            .map_err(database_error)?;
+    if exists.is_none() {
+        warn!("Stream not found");
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    set_stream_tags(&db, &stream_id, &names)
+        .await
+        .map_err(database_error)?;
+
+    info!("Updated stream tags");
+    events.publish(StreamEvent::Updated {
+        stream_id: stream_id.clone(),
+    });
+    Ok(Json(names))
+}
+
+pub async fn set_stream_tags(
+    db: &Pool<Sqlite>,
+    stream_id: &str,
+    names: &[String],
+) -> Result<(), sqlx::Error> {
+    let mut tx = db.begin().await?;
+
+    sqlx::query(r#"DELETE FROM `streamTags` WHERE `streamId` = $1"#)
+        .bind(stream_id)
+        .execute(&mut *tx)
+        .await?;
+
+    for name in names {
+        sqlx::query(r#"insert into tags (name) values ($1) on conflict (name) do nothing"#)
+            .bind(name)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            r#"insert into streamTags (streamId, tagId) select $1, `id` from `tags` where `name` = $2"#,
+        )
+        .bind(stream_id)
+        .bind(name)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await
+}
+
+pub async fn copy_stream_tags(
+    db: &Pool<Sqlite>,
+    from_stream_id: &str,
+    to_stream_id: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"insert into streamTags (streamId, tagId) select $1, `tagId` from `streamTags` where `streamId` = $2"#,
+    )
+    .bind(to_stream_id)
+    .bind(from_stream_id)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Fills in the tags of every stream with a single query.
+pub async fn attach_tags<'a, I>(db: &Pool<Sqlite>, streams: I) -> Result<(), sqlx::Error>
+where
+    I: IntoIterator<Item = &'a mut Stream>,
+{
+    let mut streams: Vec<&mut Stream> = streams.into_iter().collect();
+    if streams.is_empty() {
+        return Ok(());
+    }
+
+    let mut query = QueryBuilder::<Sqlite>::new(
+        "SELECT st.`streamId`, t.`name` FROM `streamTags` st JOIN `tags` t ON t.`id` = st.`tagId` WHERE st.`streamId` IN (",
+    );
+    let mut ids = query.separated(", ");
+    for stream in streams.iter() {
+        ids.push_bind(&stream.id);
+    }
+    query.push(") ORDER BY t.`name`");
+
+    let rows: Vec<(String, String)> = query.build_query_as().fetch_all(db).await?;
+    let mut tags: HashMap<String, Vec<String>> = HashMap::new();
+    for (stream_id, name) in rows {
+        tags.entry(stream_id).or_default().push(name);
+    }
+
+    for stream in streams.iter_mut() {
+        stream.tags = tags.remove(&stream.id).unwrap_or_default();
+    }
+    Ok(())
+}
+
+/// Lowercases, trims and dedupes tag names, rejecting empty or overlong ones.
+pub fn normalize_tags<I>(names: I) -> Option<Vec<String>>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut normalized: Vec<String> = Vec::new();
+    for name in names {
+        let name = name.trim().to_lowercase();
+        if name.is_empty() || name.len() > MAX_TAG_LENGTH || name.contains(',') {
+            return None;
+        }
+        if !normalized.contains(&name) {
+            normalized.push(name);
+        }
+    }
+    Some(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(names: &[&str]) -> Option<Vec<String>> {
+        normalize_tags(names.iter().map(|name| name.to_string()))
+    }
+
+    #[test]
+    fn normalize_tags_trims_lowercases_and_dedups() {
+        assert_eq!(
+            tags(&[" Music ", "music", "Live"]),
+            Some(vec!["music".to_string(), "live".to_string()])
+        );
+        assert_eq!(tags(&[]), Some(vec![]));
+    }
+
+    #[test]
+    fn normalize_tags_rejects_invalid_names() {
+        assert_eq!(tags(&["music", "  "]), None);
+        assert_eq!(tags(&["a,b"]), None);
+        assert_eq!(tags(&[&"x".repeat(MAX_TAG_LENGTH + 1)]), None);
+        assert!(tags(&[&"x".repeat(MAX_TAG_LENGTH)]).is_some());
+    }
+}
@@ -2,68 +2,214 @@ use anyhow::anyhow;
 use axum::{
     body::{Body, BodyDataStream},
     extract::{
-        rejection::LengthLimitError,
-        ws::{Message, WebSocket},
+        ws::{close_code, CloseFrame, Message, WebSocket},
         Query, State, WebSocketUpgrade,
     },
     response::IntoResponse,
 };
-use futures::{future::IntoStream, stream::MapErr, TryStreamExt};
+use futures::TryStreamExt;
 use tokio::{
     io::{self, AsyncBufReadExt, AsyncRead, AsyncReadExt},
-    process::{ChildStderr, ChildStdin, ChildStdout},
+    process::ChildStdin,
 };
 
-use futures::stream::StreamExt;
 use hyper::StatusCode;
 use serde::Deserialize;
 use sqlx::{Pool, Sqlite};
-use std::{
-    borrow::Borrow,
-    future::Future,
-    io::{Bytes, Error},
-    os::fd::AsFd,
-    process::Stdio,
-};
+use std::process::Stdio;
 use tokio::{fs::remove_dir, io::AsyncWriteExt};
-use tokio_util::{
-    bytes::buf::{self, Reader},
-    io::StreamReader,
-};
+use tokio_util::io::StreamReader;
 use tracing::{error, info, instrument, trace, warn};
 use utils::{database_error, format_bytes};
 
-use crate::utils;
+use crate::api::channels::{self, BroadcastKind};
+use crate::api::data::{StreamStatus, Visibility};
+use crate::api::recording;
+use crate::api::tags::{normalize_tags, set_stream_tags};
+use crate::auth::hash_password;
+use crate::encryption::{KeyRotation, Keys};
+use crate::events::{EventBus, StreamEvent};
+use crate::ffmpeg_log;
+use crate::limits::{IngestPermit, Limits};
+use crate::metrics::{FfmpegRun, METRICS};
+use crate::shutdown::Shutdown;
+use crate::storage::{Mirror, SharedStorage};
+use crate::transcode::TranscodeArgs;
+use crate::utils::{self, ResourceDir};
 
 #[derive(Deserialize, Debug)]
 pub struct UploadOptions {
     stream_name: String,
     stream_description: String,
-    width: i64,
-    height: i64,
+    width: Option<i64>,
+    height: Option<i64>,
+    channel_id: Option<String>,
+    /// Remux the finished segments into a downloadable MP4
+    #[serde(default)]
+    record: bool,
+    /// Comma separated tags
+    tags: Option<String>,
+    #[serde(default)]
+    visibility: Visibility,
+    /// A shared password viewers unlock the stream with
+    password: Option<String>,
+    /// Encrypt segments with AES-128, players fetch the keys from the key route
+    #[serde(default)]
+    encrypt: bool,
+}
+
+fn parse_tags(opts: &UploadOptions) -> Result<Vec<String>, StatusCode> {
+    let Some(tags) = &opts.tags else {
+        return Ok(Vec::new());
+    };
+    normalize_tags(tags.split(',').map(str::to_string)).ok_or_else(|| {
+        warn!(tags, "Invalid tags");
+        StatusCode::BAD_REQUEST
+    })
+}
+
+/// Hashes the upload's password, empty passwords leave the stream unprotected.
+pub async fn hash_stream_password(password: Option<String>) -> Result<Option<String>, StatusCode> {
+    match password.filter(|password| !password.is_empty()) {
+        Some(password) => hash_password(password).await.map(Some).map_err(|err| {
+            error!(%err, "Failed to hash stream password");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }),
+        None => Ok(None),
+    }
 }
 
-#[instrument(skip(query, db, req))]
-#[axum::debug_handler]
+/// The dimensions of a stream, falling back to the channel defaults when the
+/// upload does not specify them.
+async fn resolve_dimensions(
+    opts: &UploadOptions,
+    db: &Pool<Sqlite>,
+) -> Result<(i64, i64), StatusCode> {
+    let channel = match &opts.channel_id {
+        Some(channel_id) => Some(channels::get_channel_by_id(db, channel_id).await?),
+        None => None,
+    };
+
+    let width = opts
+        .width
+        .or_else(|| channel.as_ref().and_then(|c| c.default_width));
+    let height = opts
+        .height
+        .or_else(|| channel.as_ref().and_then(|c| c.default_height));
+
+    match (width, height) {
+        (Some(width), Some(height)) => Ok((width, height)),
+        _ => {
+            warn!("No width or height given and the channel has no defaults");
+            Err(StatusCode::BAD_REQUEST)
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip(
+    query,
+    db,
+    resource_dir,
+    storage,
+    keys,
+    transcode,
+    limits,
+    events,
+    shutdown,
+    req
+))]
+#[axum::debug_handler(state = crate::AppState)]
 pub async fn upload(
     Query(query): Query<UploadOptions>,
     db: State<Pool<Sqlite>>,
+    State(resource_dir): State<ResourceDir>,
+    State(storage): State<SharedStorage>,
+    State(keys): State<Keys>,
+    State(transcode): State<TranscodeArgs>,
+    State(limits): State<Limits>,
+    State(events): State<EventBus>,
+    State(shutdown): State<Shutdown>,
     req: axum::http::Request<Body>,
 ) -> Result<String, StatusCode> {
+    if shutdown.is_shutting_down() {
+        warn!("Rejecting upload, shutting down");
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    let Some(_ingest) = limits.start_ingest() else {
+        warn!("Rejecting upload, too many ingests running");
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    };
     let id = uuid::Uuid::new_v4().to_string();
+    let (width, height) = resolve_dimensions(&query, &db).await?;
+    let tags = parse_tags(&query)?;
+    let password_hash = hash_stream_password(query.password.clone()).await?;
+    let record = query.record;
 
     let file = StreamReader::new(
-        get_body_bytes(req)
+        get_body_bytes(req, limits.max_upload_bytes)
             .await?
-            .map_err(|err| io::Error::new(io::ErrorKind::Other, err)),
+            .map_err(io::Error::other),
     );
 
-    let m3u8_path = format!("index.m3u8");
-    let base_url = format!("/backend/segment/{}/", id);
-    let base_segement_file_name = format!("%03d.ts");
+    // The row goes in before ffmpeg starts, so the stream's visibility and
+    // password apply to the segments it writes from the start
+    sqlx::query(
+        r#"insert into streams (id, name, description, startTime, width, height, status, visibility, passwordHash) values ($1, $2, $3, $4, $5, $6, $7, $8, $9)"#,
+    )
+    .bind(&id)
+    .bind(&query.stream_name)
+    .bind(&query.stream_description)
+    .bind(chrono::Utc::now())
+    .bind(width)
+    .bind(height)
+    .bind(StreamStatus::Processing)
+    .bind(query.visibility)
+    .bind(password_hash)
+    .execute(&*db)
+    .await
+    .map_err(database_error)?;
+    info!("inserted stream");
+    if let Err(err) = set_stream_tags(&db, &id, &tags).await {
+        error!(%err, "Failed to tag stream");
+    }
+    events.publish(StreamEvent::StreamCreated {
+        stream_id: id.clone(),
+    });
+
+    if let Some(channel_id) = &query.channel_id {
+        if let Err(err) =
+            channels::start_broadcast(&db, channel_id, &id, BroadcastKind::Upload).await
+        {
+            error!(%err, "Failed to start broadcast");
+        }
+    }
 
-    let rescources_dir = format!("resources/{}", id);
-    tokio::fs::create_dir(rescources_dir.clone()).await.unwrap();
+    let m3u8_path = "index.m3u8".to_string();
+    let base_segement_file_name = "%03d.ts".to_string();
+    let key_rotation = if query.encrypt {
+        match KeyRotation::start(&keys, &db, &id).await {
+            Ok(key_rotation) => Some(key_rotation),
+            Err(err) => {
+                error!(%err, "Failed to create stream key");
+                ffmpeg_log::save_failure_reason(&db, &id, &err.to_string()).await;
+                finish_stream(&db, &events, &id, StreamStatus::Failed).await;
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+    } else {
+        None
+    };
+
+    let rescources_dir = resource_dir.stream_dir(&id);
+    if let Err(err) = tokio::fs::create_dir(&rescources_dir).await {
+        error!(%err, "Failed to create resources dir");
+        ffmpeg_log::save_failure_reason(&db, &id, &err.to_string()).await;
+        finish_stream(&db, &events, &id, StreamStatus::Failed).await;
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    let mirror = Mirror::start(&storage, &resource_dir, &id);
+    let ffmpeg_run = FfmpegRun::start("upload");
     let mut command = tokio::process::Command::new("ffmpeg");
 
     let cmd = command
@@ -78,9 +224,9 @@ pub async fn upload(
             "-i",
             "pipe:0",
             "-force_key_frames",
-            "expr:gte(t,n_forced*3)",
+            &transcode.force_key_frames(),
             "-hls_time",
-            "2",
+            &transcode.hls_time.to_string(),
             // Not for live
             "-live_start_index",
             "0",
@@ -92,15 +238,17 @@ pub async fn upload(
             "independent_segments",
             "-hls_segment_filename",
             &base_segement_file_name,
-            "-hls_base_url",
-            &base_url,
             "-f",
             "hls",
             "-c:v",
-            "h264_videotoolbox",
-            &m3u8_path,
+            &transcode.video_codec,
         ])
-        .current_dir(format!("resources/{}", id))
+        .args(key_rotation.iter().flat_map(KeyRotation::ffmpeg_args))
+        .arg(&m3u8_path)
+        .current_dir(&rescources_dir)
+        // Keep ctrl-c in the terminal from reaching ffmpeg, on shutdown we close
+        // its stdin instead so it can finish the playlist
+        .process_group(0)
         .spawn();
 
     let mut child = match cmd {
@@ -111,11 +259,13 @@ pub async fn upload(
             if let Err(err) = remove_dir(rescources_dir).await {
                 error!(%err, "Failed to remove resources dir");
             }
+            ffmpeg_log::save_failure_reason(&db, &id, &error.to_string()).await;
+            finish_stream(&db, &events, &id, StreamStatus::Failed).await;
             return Err(StatusCode::INTERNAL_SERVER_ERROR);
         }
     };
 
-    let mut child_stderr = match child.stderr.take() {
+    let child_stderr = match child.stderr.take() {
         Some(stderr) => stderr,
         None => {
             let err = anyhow!("No stdout");
@@ -123,11 +273,12 @@ pub async fn upload(
             if let Err(err) = remove_dir(rescources_dir).await {
                 error!(%err, "Failed to remove resources dir");
             }
+            finish_stream(&db, &events, &id, StreamStatus::Failed).await;
             return Err(StatusCode::INTERNAL_SERVER_ERROR);
         }
     };
 
-    let mut child_stdout = match child.stdout.take() {
+    let child_stdout = match child.stdout.take() {
         Some(stdout) => stdout,
         None => {
             let err = anyhow!("No stdout");
@@ -135,6 +286,7 @@ pub async fn upload(
             if let Err(err) = remove_dir(rescources_dir).await {
                 error!(%err, "Failed to remove resources dir");
             }
+            finish_stream(&db, &events, &id, StreamStatus::Failed).await;
             return Err(StatusCode::INTERNAL_SERVER_ERROR);
         }
     };
@@ -147,19 +299,27 @@ pub async fn upload(
             if let Err(err) = remove_dir(rescources_dir).await {
                 error!(%err, "Failed to remove resources dir");
             }
+            finish_stream(&db, &events, &id, StreamStatus::Failed).await;
             return Err(StatusCode::INTERNAL_SERVER_ERROR);
         }
     };
 
     let resources_dir_for_write = rescources_dir.clone();
+    let id_for_write = id.clone();
+    let shutdown_for_write = shutdown.clone();
     let write_to_stdin_future = async move {
-        if let Err(err) = read_into_stdin(&mut child_stdin, file).await {
-            error!(%err, "Failed to write to ffmpeg process stdin");
-            if let Err(err) = remove_dir(resources_dir_for_write).await {
-                error!(%err, "Failed to remove resources dir");
-            }
-            return ();
-        }
+        let interrupted =
+            match read_into_stdin(&mut child_stdin, file, &id_for_write, &shutdown_for_write).await
+            {
+                Ok(interrupted) => interrupted,
+                Err(err) => {
+                    error!(%err, "Failed to write to ffmpeg process stdin");
+                    if let Err(err) = remove_dir(resources_dir_for_write).await {
+                        error!(%err, "Failed to remove resources dir");
+                    }
+                    return false;
+                }
+            };
 
         if let Err(err) = child_stdin.shutdown().await {
             error!(%err, "Failed to write shutdown stdin");
@@ -169,9 +329,9 @@ pub async fn upload(
         }
 
         drop(child_stdin);
+        interrupted
     };
 
-    let id_for_sql = id.clone();
     let resources_dir_for_read_stdout = rescources_dir.clone();
     let read_std_out_future = async move {
         if let Err(err) = read_std_out(child_stdout).await {
@@ -179,43 +339,14 @@ pub async fn upload(
             if let Err(err) = remove_dir(resources_dir_for_read_stdout).await {
                 error!(%err, "Failed to remove resources dir");
             }
-            return ();
         }
-        // Here we have crated the first segement so we can persist the stream
-
-        if let Err(err) = sqlx::query(
-            r#"insert into streams (id, name, description, startTime, width, height) values ($1, $2, $3, $4, $5, $6)"#,
-        )
-        .bind(&id_for_sql)
-        .bind(query.stream_name)
-        .bind(query.stream_description)
-        .bind(chrono::Utc::now())
-        .bind(query.width)
-        .bind(query.height)
-        .execute(&*db)
-        .await
-        .map_err(database_error) {
-            error!(%err, "Failed to insert stream");
-            if let Err(err) = remove_dir(resources_dir_for_read_stdout).await {
-                error!(%err, "Failed to remove resources dir");
-            }
-        };
-        info!("inserted stream");
     };
 
-    let resources_dir_for_read = rescources_dir.clone();
-    let read_std_err_future = async move {
-        if let Err(err) = read_std_out(child_stderr).await {
-            error!(%err, "Failed to read ffmpeg process sterr");
-            if let Err(err) = remove_dir(resources_dir_for_read).await {
-                error!(%err, "Failed to remove resources dir");
-            }
-        }
-    };
+    let capture_std_err_future = ffmpeg_log::capture(child_stderr, resource_dir.log_path(&id));
 
-    tokio::join!(
+    let (_, std_err_tail, interrupted) = tokio::join!(
         read_std_out_future,
-        read_std_err_future,
+        capture_std_err_future,
         write_to_stdin_future,
     );
 
@@ -227,29 +358,82 @@ pub async fn upload(
             if let Err(err) = remove_dir(rescources_dir).await {
                 error!(%err, "Failed to remove resources dir");
             }
+            ffmpeg_log::save_failure_reason(&db, &id, &err.to_string()).await;
+            finish_stream(&db, &events, &id, StreamStatus::Failed).await;
             return Err(StatusCode::INTERNAL_SERVER_ERROR);
         }
     };
+    mirror.finish().await;
+    if let Some(key_rotation) = key_rotation {
+        key_rotation.finish().await;
+    }
 
     if !output.success() {
-        error!("Failed to process file");
+        let reason = std_err_tail.join("\n");
+        error!(%output, reason, "Failed to process file");
         if let Err(err) = remove_dir(rescources_dir).await {
             error!(%err, "Failed to remove resources dir");
         }
+        ffmpeg_log::save_failure_reason(&db, &id, &reason).await;
+        finish_stream(&db, &events, &id, StreamStatus::Failed).await;
         return Err(StatusCode::INTERNAL_SERVER_ERROR);
     }
     info!("proccesed file");
+    ffmpeg_run.succeeded();
+
+    if interrupted {
+        warn!("Upload interrupted by shutdown");
+        finish_stream(&db, &events, &id, StreamStatus::Interrupted).await;
+        return Ok(id);
+    }
+    finish_stream(&db, &events, &id, StreamStatus::Ended).await;
+
+    if record {
+        let id = id.clone();
+        // Shutdown waits for the recording, a half written one would be lost
+        tokio::spawn(shutdown.track(async move {
+            if let Err(err) =
+                recording::remux_to_mp4(&db, &keys, &storage, &id, &rescources_dir).await
+            {
+                error!(%err, "Failed to record stream");
+            }
+        }));
+    }
     Ok(id)
 }
 
-async fn read_std_out<R: AsyncRead>(buff: R) -> Result<(), anyhow::Error>
+/// Records the final status of a stream and ends its broadcast.
+pub async fn finish_stream(db: &Pool<Sqlite>, events: &EventBus, id: &str, status: StreamStatus) {
+    match sqlx::query(r#"UPDATE `streams` SET `status` = $1, `endTime` = $2 WHERE `id` = $3"#)
+        .bind(status)
+        .bind(chrono::Utc::now())
+        .bind(id)
+        .execute(db)
+        .await
+    {
+        Ok(updated) if updated.rows_affected() > 0 => events.publish(StreamEvent::Ended {
+            stream_id: id.to_string(),
+            status: status.as_str().to_string(),
+        }),
+        Ok(_) => (),
+        Err(err) => error!(%err, "Failed to update stream status"),
+    }
+
+    if let Err(err) = channels::end_broadcast(db, id).await {
+        error!(%err, "Failed to end broadcast");
+    }
+}
+
+async fn read_std_out<R>(buff: R) -> Result<(), anyhow::Error>
 where
-    R: Unpin,
+    R: AsyncRead + Unpin,
 {
     let mut buff_reader = io::BufReader::with_capacity(2000 * 1024, buff);
     let mut line = String::new();
     loop {
+        line.clear();
         match buff_reader.read_line(&mut line).await {
+            Ok(0) => return Ok(()),
             Ok(_) => (),
             Err(err) => {
                 if err.kind() == io::ErrorKind::UnexpectedEof {
@@ -267,57 +451,194 @@ where
     }
 }
 
-#[instrument(skip(ws))]
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip(
+    ws,
+    query,
+    db,
+    resource_dir,
+    storage,
+    keys,
+    transcode,
+    limits,
+    events,
+    shutdown
+))]
 pub async fn upload_ws(
     Query(query): Query<UploadOptions>,
     db: State<Pool<Sqlite>>,
+    State(resource_dir): State<ResourceDir>,
+    State(storage): State<SharedStorage>,
+    State(keys): State<Keys>,
+    State(transcode): State<TranscodeArgs>,
+    State(limits): State<Limits>,
+    State(events): State<EventBus>,
+    State(shutdown): State<Shutdown>,
     ws: WebSocketUpgrade,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| handle_ws(socket, query, db))
+) -> Result<impl IntoResponse, StatusCode> {
+    if shutdown.is_shutting_down() {
+        warn!("Rejecting ingest, shutting down");
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    let Some(ingest) = limits.start_ingest() else {
+        warn!("Rejecting ingest, too many ingests running");
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    };
+    let dimensions = resolve_dimensions(&query, &db).await?;
+    let tags = parse_tags(&query)?;
+    let password_hash = hash_stream_password(query.password.clone()).await?;
+    Ok(ws.on_upgrade(move |socket| {
+        shutdown.track(handle_ws(
+            socket,
+            query,
+            dimensions,
+            tags,
+            password_hash,
+            db,
+            resource_dir,
+            storage,
+            keys,
+            transcode,
+            ingest,
+            events,
+            shutdown.clone(),
+        ))
+    }))
 }
 
-async fn handle_ws(socket: WebSocket, opts: UploadOptions, db: State<Pool<Sqlite>>) -> () {
+#[allow(clippy::too_many_arguments)]
+async fn handle_ws(
+    socket: WebSocket,
+    opts: UploadOptions,
+    (width, height): (i64, i64),
+    tags: Vec<String>,
+    password_hash: Option<String>,
+    db: State<Pool<Sqlite>>,
+    resource_dir: ResourceDir,
+    storage: SharedStorage,
+    keys: Keys,
+    transcode: TranscodeArgs,
+    _ingest: IngestPermit,
+    events: EventBus,
+    shutdown: Shutdown,
+) {
     let id = uuid::Uuid::new_v4().to_string();
     match sqlx::query(
-        r#"insert into streams (id, name, description, startTime, width, height) values ($1, $2, $3, $4, $5, $6)"#,
+        r#"insert into streams (id, name, description, startTime, width, height, status, visibility, passwordHash) values ($1, $2, $3, $4, $5, $6, $7, $8, $9)"#,
     )
     .bind(&id)
     .bind(opts.stream_name)
     .bind(opts.stream_description)
     .bind(chrono::Utc::now())
-    .bind(opts.width)
-    .bind(opts.height)
+    .bind(width)
+    .bind(height)
+    .bind(StreamStatus::Live)
+    .bind(opts.visibility)
+    .bind(password_hash)
     .execute(&*db)
     .await {
         Ok(_) => (),
         Err(err) => {
             error!(?err, "Failed to insert stream");
-            return ();
+            return;
         }
     };
     info!("inserted stream");
+    if let Err(err) = set_stream_tags(&db, &id, &tags).await {
+        error!(%err, "Failed to tag stream");
+    }
+    events.publish(StreamEvent::StreamCreated {
+        stream_id: id.clone(),
+    });
+    events.publish(StreamEvent::WentLive {
+        stream_id: id.clone(),
+    });
+
+    if let Some(channel_id) = &opts.channel_id {
+        if let Err(err) = channels::start_broadcast(&db, channel_id, &id, BroadcastKind::Live).await
+        {
+            error!(%err, "Failed to start broadcast");
+        }
+    }
 
-    let m3u8_path = format!("index.m3u8");
-    let base_url = format!("/backend/segment/{}/", id);
-    let base_segement_file_name = format!("%03d.ts");
+    let key_rotation = if opts.encrypt {
+        match KeyRotation::start(&keys, &db, &id).await {
+            Ok(key_rotation) => Some(key_rotation),
+            Err(err) => {
+                error!(%err, "Failed to create stream key");
+                ffmpeg_log::save_failure_reason(&db, &id, &err.to_string()).await;
+                finish_stream(&db, &events, &id, StreamStatus::Failed).await;
+                return;
+            }
+        }
+    } else {
+        None
+    };
+    let ingested = ingest_ws(
+        socket,
+        &id,
+        &resource_dir,
+        &storage,
+        &transcode,
+        key_rotation.as_ref(),
+        &shutdown,
+    )
+    .await;
+    if let Some(key_rotation) = key_rotation {
+        key_rotation.finish().await;
+    }
+
+    let status = match &ingested {
+        Ok(status) => *status,
+        Err(reason) => {
+            ffmpeg_log::save_failure_reason(&db, &id, reason).await;
+            StreamStatus::Failed
+        }
+    };
+    finish_stream(&db, &events, &id, status).await;
+
+    if ingested == Ok(StreamStatus::Ended) && opts.record {
+        if let Err(err) =
+            recording::remux_to_mp4(&db, &keys, &storage, &id, &resource_dir.stream_dir(&id)).await
+        {
+            error!(%err, "Failed to record stream");
+        }
+    }
+}
 
-    let rescources_dir = format!("resources/{}", id);
-    if let Err(err) = tokio::fs::create_dir(rescources_dir.clone()).await {
+/// Transcodes the websocket into the stream's playlist, returning whether it
+/// ended or was interrupted by shutdown, or the reason the transcode failed.
+async fn ingest_ws(
+    socket: WebSocket,
+    id: &str,
+    resource_dir: &ResourceDir,
+    storage: &SharedStorage,
+    transcode: &TranscodeArgs,
+    key_rotation: Option<&KeyRotation>,
+    shutdown: &Shutdown,
+) -> Result<StreamStatus, String> {
+    let m3u8_path = "index.m3u8".to_string();
+    let base_segement_file_name = "%03d.ts".to_string();
+
+    let rescources_dir = resource_dir.stream_dir(id);
+    if let Err(err) = tokio::fs::create_dir(&rescources_dir).await {
         error!(%err, "Failed to create resources dir");
-        return ();
+        return Err(err.to_string());
     };
+    let mirror = Mirror::start(storage, resource_dir, id);
+    let ffmpeg_run = FfmpegRun::start("live");
     let mut command = tokio::process::Command::new("ffmpeg");
 
     let cmd = command
         .stdin(Stdio::piped())
-        // todo maybe log this?
+        .stderr(Stdio::piped())
         .args(vec![
             "-i",
             "pipe:0",
             "-force_key_frames",
-            "expr:gte(t,n_forced*3)",
+            &transcode.force_key_frames(),
             "-hls_time",
-            "2",
+            &transcode.hls_time.to_string(),
             "-hls_list_size",
             "0",
             "-tune",
@@ -326,15 +647,17 @@ async fn handle_ws(socket: WebSocket, opts: UploadOptions, db: State<Pool<Sqlite
             "independent_segments",
             "-hls_segment_filename",
             &base_segement_file_name,
-            "-hls_base_url",
-            &base_url,
             "-f",
             "hls",
             "-c:v",
-            "h264_videotoolbox",
-            &m3u8_path,
+            &transcode.video_codec,
         ])
-        .current_dir(rescources_dir.clone())
+        .args(key_rotation.into_iter().flat_map(KeyRotation::ffmpeg_args))
+        .arg(&m3u8_path)
+        .current_dir(&rescources_dir)
+        // Keep ctrl-c in the terminal from reaching ffmpeg, on shutdown we close
+        // its stdin instead so it can finish the playlist
+        .process_group(0)
         .spawn();
 
     let mut child = match cmd {
@@ -345,7 +668,19 @@ async fn handle_ws(socket: WebSocket, opts: UploadOptions, db: State<Pool<Sqlite
             if let Err(err) = remove_dir(rescources_dir).await {
                 error!(%err, "Failed to remove resources dir");
             }
-            return ();
+            return Err(error.to_string());
+        }
+    };
+
+    let std_err_capture = match child.stderr.take() {
+        Some(stderr) => tokio::spawn(ffmpeg_log::capture(stderr, resource_dir.log_path(id))),
+        None => {
+            let err = anyhow!("No stderr");
+            error!(%err, "could not take child stderr");
+            if let Err(err) = remove_dir(rescources_dir).await {
+                error!(%err, "Failed to remove resources dir");
+            }
+            return Err(err.to_string());
         }
     };
 
@@ -357,24 +692,27 @@ async fn handle_ws(socket: WebSocket, opts: UploadOptions, db: State<Pool<Sqlite
             if let Err(err) = remove_dir(rescources_dir).await {
                 error!(%err, "Failed to remove resources dir");
             }
-            return ();
+            return Err(err.to_string());
         }
     };
 
-    if let Err(err) = read_ws_into_stdin(&mut child_stdin, socket).await {
-        error!(%err, "Failed to write to ffmpeg process stdin");
-        if let Err(err) = remove_dir(rescources_dir).await {
-            error!(%err, "Failed to remove resources dir");
+    let interrupted = match read_ws_into_stdin(&mut child_stdin, socket, id, shutdown).await {
+        Ok(interrupted) => interrupted,
+        Err(err) => {
+            error!(%err, "Failed to write to ffmpeg process stdin");
+            if let Err(err) = remove_dir(rescources_dir).await {
+                error!(%err, "Failed to remove resources dir");
+            }
+            return Err(err.to_string());
         }
-        return ();
-    }
+    };
 
     if let Err(err) = child_stdin.shutdown().await {
         error!(%err, "Failed to write shutdown stdin");
         if let Err(err) = remove_dir(rescources_dir).await {
             error!(%err, "Failed to remove resources dir");
         }
-        return ();
+        return Err(err.to_string());
     }
 
     drop(child_stdin);
@@ -387,32 +725,45 @@ async fn handle_ws(socket: WebSocket, opts: UploadOptions, db: State<Pool<Sqlite
             if let Err(err) = remove_dir(rescources_dir).await {
                 error!(%err, "Failed to remove resources dir");
             }
-            return ();
+            return Err(err.to_string());
         }
     };
+    mirror.finish().await;
 
+    let std_err_tail = std_err_capture.await.unwrap_or_default();
     if !output.success() {
-        error!("Failed to process file");
+        let reason = std_err_tail.join("\n");
+        error!(%output, reason, "Failed to process file");
         if let Err(err) = remove_dir(rescources_dir).await {
             error!(%err, "Failed to remove resources dir");
         }
-        return ();
+        return Err(reason);
     }
     info!("proccesed file");
-    ()
+    ffmpeg_run.succeeded();
+    if interrupted {
+        warn!("Ingest interrupted by shutdown");
+        return Ok(StreamStatus::Interrupted);
+    }
+    Ok(StreamStatus::Ended)
 }
 
-const ONE_KB: usize = 1024;
-const ONE_MB: usize = ONE_KB * 1024;
-const ONE_GB: usize = ONE_MB * 1024;
-
-async fn get_body_bytes(req: axum::http::Request<Body>) -> Result<BodyDataStream, StatusCode> {
+/// The body of an upload, rejected up front when it declares more than
+/// `max_bytes`. hyper makes sure it is no longer than it declares.
+async fn get_body_bytes(
+    req: axum::http::Request<Body>,
+    max_bytes: Option<u64>,
+) -> Result<BodyDataStream, StatusCode> {
     let request_content_length = match req
         .headers()
         .get("content-length")
-        .map(|bytes| bytes.to_str().map(|s| s.parse::<i64>()))
+        .map(|bytes| bytes.to_str().map(|s| s.parse::<u64>()))
     {
-        Some(Ok(Ok(bytes))) => format_bytes(bytes),
+        Some(Ok(Ok(bytes))) if max_bytes.is_some_and(|max_bytes| bytes > max_bytes) => {
+            warn!(bytes, max_bytes, "Upload is too large");
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        Some(Ok(Ok(bytes))) => format_bytes(bytes as i64),
         Some(Ok(Err(err))) => {
             error!(?err, "Error parsing content length {}", err);
             return Err(StatusCode::BAD_REQUEST);
@@ -434,46 +785,79 @@ async fn get_body_bytes(req: axum::http::Request<Body>) -> Result<BodyDataStream
     Ok(req.into_body().into_data_stream())
 }
 
-async fn read_into_stdin<T>(child_stdin: &mut ChildStdin, mut file: T) -> Result<(), anyhow::Error>
+/// Feeds the upload into ffmpeg until it ends, returning whether shutdown
+/// interrupted it first.
+async fn read_into_stdin<T>(
+    child_stdin: &mut ChildStdin,
+    mut file: T,
+    stream_id: &str,
+    shutdown: &Shutdown,
+) -> Result<bool, anyhow::Error>
 where
     T: AsyncReadExt + Unpin,
 {
     let mut buff = vec![0; 1024 * 50];
     loop {
-        match file.read_exact(&mut buff).await {
+        let read = tokio::select! {
+            read = file.read(&mut buff) => read,
+            _ = shutdown.cancelled() => {
+                info!("Shutting down, closing stdin");
+                return Ok(true);
+            }
+        };
+        match read {
+            Ok(0) => {
+                info!("Finished writing to stdin");
+                return Ok(false);
+            }
             // TODO check for to large
-            Ok(written_bytes) => {
-                if let Err(err) = &mut child_stdin.write_all(&mut buff[..written_bytes]).await {
+            Ok(read_bytes) => {
+                if let Err(err) = child_stdin.write_all(&buff[..read_bytes]).await {
                     error!(%err, "Failed to write to ffmpeg process stdin");
                     return Err(anyhow!("Failed to write to ffmpeg process stdin"));
                 }
+                METRICS.record_ingested(stream_id, read_bytes);
             }
             Err(err) => {
-                if err.kind() == io::ErrorKind::UnexpectedEof {
-                    info!("Finished writing to stdin");
-
-                    break;
-                }
+                error!(%err, "Failed to read upload body");
                 return Err(anyhow!("Failed to write to ffmpeg process stdin"));
             }
         }
     }
-    Ok(())
 }
 
-#[instrument(skip(child_stdin, ws_stream))]
+/// Feeds websocket messages into ffmpeg until the client closes it, returning
+/// whether shutdown interrupted it first.
+#[instrument(skip(child_stdin, ws_stream, shutdown))]
 async fn read_ws_into_stdin(
     child_stdin: &mut ChildStdin,
     mut ws_stream: WebSocket,
-) -> Result<(), anyhow::Error> {
+    stream_id: &str,
+    shutdown: &Shutdown,
+) -> Result<bool, anyhow::Error> {
     loop {
-        match ws_stream.recv().await {
+        let msg = tokio::select! {
+            msg = ws_stream.recv() => msg,
+            _ = shutdown.cancelled() => {
+                info!("Shutting down, closing websocket");
+                let close = Message::Close(Some(CloseFrame {
+                    code: close_code::AWAY,
+                    reason: "Server is shutting down".into(),
+                }));
+                if let Err(err) = ws_stream.send(close).await {
+                    warn!(%err, "Failed to close websocket");
+                }
+                return Ok(true);
+            }
+        };
+        match msg {
             Some(Ok(Message::Binary(msg))) => {
                 trace!("writing {:?} bytes to stdin", msg.len());
-                if let Err(err) = &mut child_stdin.write_all(&msg).await {
+                if let Err(err) = child_stdin.write_all(&msg).await {
                     error!(%err, "Failed to write to ffmpeg process stdin");
                     return Err(anyhow!("Failed to write to ffmpeg process stdin"));
                 }
+                METRICS.record_ingested(stream_id, msg.len());
                 trace!("wrote {:?} bytes to stdin", msg.len());
             }
             Some(Ok(msg)) => {
@@ -485,10 +869,8 @@ async fn read_ws_into_stdin(
             }
             None => {
                 info!("Finished writing to stdin");
-                break;
+                return Ok(false);
             }
         }
     }
-
-    Ok(())
 }
@@ -0,0 +1,222 @@
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use hyper::StatusCode;
+use serde::Deserialize;
+use sqlx::{Pool, Sqlite};
+use tracing::{info, instrument, warn};
+
+use crate::api::data::{Webhook, WebhookDelivery};
+use crate::auth::Admin;
+use crate::utils::database_error;
+use crate::webhooks::EVENT_NAMES;
+
+#[derive(Deserialize, Debug)]
+pub struct CreateWebhook {
+    url: String,
+    /// Defaults to every event
+    events: Option<Vec<String>>,
+    /// Generated when not given
+    secret: Option<String>,
+}
+
+/// Webhooks receive the events of every stream whatever its visibility, so
+/// they are admin only.
+#[instrument(skip(db, body))]
+pub async fn create_webhook(
+    _admin: Admin,
+    db: State<Pool<Sqlite>>,
+    Json(body): Json<CreateWebhook>,
+) -> Result<Json<Webhook>, StatusCode> {
+    if !(body.url.starts_with("http://") || body.url.starts_with("https://")) {
+        warn!(url = body.url, "Webhook url is not http");
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let events = match body.events {
+        Some(events) if !events.is_empty() => {
+            if let Some(unknown) = events
+                .iter()
+                .find(|event| !EVENT_NAMES.contains(&event.as_str()))
+            {
+                warn!(unknown, "Unknown webhook event");
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            events.join(",")
+        }
+        _ => "*".to_string(),
+    };
+
+    let webhook = Webhook {
+        id: uuid::Uuid::new_v4().to_string(),
+        url: body.url,
+        secret: Some(
+            body.secret
+                .unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string()),
+        ),
+        events,
+        created_at: chrono::Utc::now(),
+    };
+
+    sqlx::query(
+        r#"insert into webhooks (id, url, secret, events, createdAt) values ($1, $2, $3, $4, $5)"#,
+    )
+    .bind(&webhook.id)
+    .bind(&webhook.url)
+    .bind(&webhook.secret)
+    .bind(&webhook.events)
+    .bind(webhook.created_at)
+    .execute(&*db)
+    .await
+    .map_err(database_error)?;
+
+    info!(webhook_id = webhook.id, "Created webhook");
+    Ok(Json(webhook))
+}
+
+#[instrument(skip(db))]
+pub async fn get_webhooks(
+    _admin: Admin,
+    db: State<Pool<Sqlite>>,
+) -> Result<Json<Vec<Webhook>>, StatusCode> {
+    let webhooks = sqlx::query_as(
+        r#"SELECT `id`, `url`, `events`, `createdAt` FROM `webhooks` ORDER BY `createdAt` DESC"#,
+    )
+    .fetch_all(&*db)
+    .await
+    .map_err(database_error)?;
+
+    Ok(Json(webhooks))
+}
+
+#[instrument(skip(db))]
+pub async fn delete_webhook(
+    _admin: Admin,
+    Path(webhook_id): Path<String>,
+    db: State<Pool<Sqlite>>,
+) -> Result<(), StatusCode> {
+    let deleted = sqlx::query(r#"DELETE FROM `webhooks` WHERE `id` = $1"#)
+        .bind(&webhook_id)
+        .execute(&*db)
+        .await
+        .map_err(database_error)?;
+
+    if deleted.rows_affected() == 0 {
+        warn!("Webhook not found");
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    info!("Deleted webhook");
+    Ok(())
+}
+
+#[instrument(skip(db))]
+pub async fn get_deliveries(
+    _admin: Admin,
+    Path(webhook_id): Path<String>,
+    db: State<Pool<Sqlite>>,
+) -> Result<Json<Vec<WebhookDelivery>>, StatusCode> {
+    let deliveries = sqlx::query_as(
+        r#"SELECT * FROM `webhookDeliveries` WHERE `webhookId` = $1 ORDER BY `createdAt` DESC LIMIT 100"#,
+    )
+    .bind(&webhook_id)
+    .fetch_all(&*db)
+    .await
+    .map_err(database_error)?;
+
+    Ok(Json(deliveries))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        extract::FromRef,
+        http::{header, Method, Request},
+        routing::{delete, get},
+        Router,
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::auth::AdminToken;
+    use crate::utils::test_db;
+
+    #[derive(Clone, FromRef)]
+    struct TestState {
+        db: Pool<Sqlite>,
+        admin_token: AdminToken,
+    }
+
+    async fn app() -> Router {
+        Router::new()
+            .route("/webhooks", get(get_webhooks).post(create_webhook))
+            .route("/webhooks/:webhookId", delete(delete_webhook))
+            .route("/webhooks/:webhookId/deliveries", get(get_deliveries))
+            .with_state(TestState {
+                db: test_db().await,
+                admin_token: AdminToken(Some("admin".into())),
+            })
+    }
+
+    fn request(method: Method, uri: &str, token: Option<&str>) -> Request<Body> {
+        let mut req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json");
+        if let Some(token) = token {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        req.body(Body::from(r#"{"url":"https://example.com/hook"}"#))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn every_route_needs_the_admin_token() {
+        let app = app().await;
+        for (method, uri) in [
+            (Method::POST, "/webhooks"),
+            (Method::GET, "/webhooks"),
+            (Method::DELETE, "/webhooks/abc"),
+            (Method::GET, "/webhooks/abc/deliveries"),
+        ] {
+            for token in [None, Some("wrong")] {
+                let response = app
+                    .clone()
+                    .oneshot(request(method.clone(), uri, token))
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    response.status(),
+                    StatusCode::UNAUTHORIZED,
+                    "{} {}",
+                    method,
+                    uri
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn admins_manage_webhooks() {
+        let app = app().await;
+        let response = app
+            .clone()
+            .oneshot(request(Method::POST, "/webhooks", Some("admin")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .oneshot(request(Method::GET, "/webhooks", Some("admin")))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let webhooks: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(webhooks[0]["url"], "https://example.com/hook");
+    }
+}
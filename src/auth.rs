@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts},
+    http::{header, request::Parts},
+};
+use hyper::StatusCode;
+use tracing::warn;
+
+/// The bearer token admin only endpoints require, they are disabled when unset.
+#[derive(Clone, Default)]
+pub struct AdminToken(pub Option<Arc<str>>);
+
+impl std::fmt::Debug for AdminToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("AdminToken")
+            .field(&self.0.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+/// Extracting this rejects requests that do not carry the admin token.
+#[derive(Debug)]
+pub struct Admin;
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Admin
+where
+    S: Send + Sync,
+    AdminToken: FromRef<S>,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let AdminToken(Some(token)) = AdminToken::from_ref(state) else {
+            warn!("Admin endpoints are disabled, no admin token is configured");
+            return Err(StatusCode::FORBIDDEN);
+        };
+
+        if carries_token(parts, &token) {
+            Ok(Admin)
+        } else {
+            warn!("Missing or invalid admin token");
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
+}
+
+/// Whether the request carries the admin token, for endpoints that show admins
+/// more but are open to everyone. Unlike [`Admin`] it never rejects or logs.
+#[derive(Debug, Clone, Copy)]
+pub struct IsAdmin(pub bool);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for IsAdmin
+where
+    S: Send + Sync,
+    AdminToken: FromRef<S>,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        Ok(IsAdmin(match AdminToken::from_ref(state) {
+            AdminToken(Some(token)) => carries_token(parts, &token),
+            AdminToken(None) => false,
+        }))
+    }
+}
+
+fn carries_token(parts: &Parts, token: &str) -> bool {
+    parts
+        .headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|provided| constant_time_eq(provided.as_bytes(), token.as_bytes()))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Hashes a stream password with argon2, off the async runtime since it is
+/// deliberately slow.
+pub async fn hash_password(password: String) -> Result<String, anyhow::Error> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::encode_b64(uuid::Uuid::new_v4().as_bytes())
+            .map_err(|err| anyhow!("Failed to encode salt: {}", err))?;
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|err| anyhow!("Failed to hash password: {}", err))
+    })
+    .await?
+}
+
+pub async fn verify_password(hash: String, password: String) -> bool {
+    tokio::task::spawn_blocking(move || {
+        PasswordHash::new(&hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    })
+    .await
+    .unwrap_or(false)
+}
@@ -0,0 +1,283 @@
+//! Layered configuration. Every setting is a command line flag with an
+//! environment variable and can also be set in a TOML file passed with
+//! `--config`. Flags win over environment variables, which win over the file,
+//! which wins over the built in defaults.
+//!
+//! Keys in the file are the flag names without the leading dashes, grouped
+//! into a table per section:
+//!
+//! ```toml
+//! rescource-dir = "/var/lib/can-i-get-a-stream"
+//!
+//! [server]
+//! port = 8080
+//!
+//! [transcoding]
+//! video-codec = "libx264"
+//!
+//! [cors]
+//! cors-allowed-origins = ["https://example.com"]
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use clap::{error::ErrorKind, Arg, Command, Parser};
+
+/// The sections of the file and the argument groups their settings come from.
+/// Settings outside of a table are the ones that belong to no group.
+const SECTIONS: &[(&str, &str)] = &[
+    ("server", "ServerArgs"),
+    ("tls", "TlsArgs"),
+    ("storage", "StorageArgs"),
+    ("transcoding", "TranscodeArgs"),
+    ("limits", "LimitsArgs"),
+    ("cors", "CorsArgs"),
+    ("playback", "PlaybackArgs"),
+    ("encryption", "EncryptionArgs"),
+    ("retention", "RetentionPolicy"),
+    ("log", "LogArgs"),
+];
+
+const CONFIG_ENV: &str = "CONFIG_FILE";
+
+/// Parses the command line on top of the environment and the config file,
+/// exiting with a usage error when any of them is invalid.
+pub fn parse<T: Parser>() -> T {
+    let mut command = T::command().arg(
+        Arg::new("config")
+            .long("config")
+            .env(CONFIG_ENV)
+            .value_name("FILE")
+            .help("TOML file with settings, overridden by the environment and flags"),
+    );
+    if let Some(path) = config_path() {
+        command = match apply_file(command.clone(), &path) {
+            Ok(command) => command,
+            Err(err) => command
+                .error(
+                    ErrorKind::ValueValidation,
+                    format!("invalid config file {}: {}", path.display(), err),
+                )
+                .exit(),
+        };
+    }
+
+    let matches = command.get_matches();
+    T::from_arg_matches(&matches).unwrap_or_else(|err| err.exit())
+}
+
+/// The config file has to be known before the rest of the arguments are
+/// parsed, since it provides their defaults.
+fn config_path() -> Option<PathBuf> {
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        }
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            return Some(path.into());
+        }
+    }
+    std::env::var_os(CONFIG_ENV).map(PathBuf::from)
+}
+
+fn apply_file(mut command: Command, path: &Path) -> Result<Command, String> {
+    let text = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+    let table: toml::Table = text
+        .parse()
+        .map_err(|err: toml::de::Error| err.to_string())?;
+
+    for (key, value) in table {
+        match value {
+            toml::Value::Table(settings) => {
+                let section = SECTIONS
+                    .iter()
+                    .find(|(section, _)| *section == key)
+                    .ok_or_else(|| format!("unknown section [{}]", key))?;
+                for (name, value) in settings {
+                    command = set_default(command, Some(section), &name, value)?;
+                }
+            }
+            value => command = set_default(command, None, &key, value)?,
+        }
+    }
+    Ok(command)
+}
+
+/// Makes a setting from the file the default of its argument, so the
+/// environment and flags still override it and it is validated like them.
+fn set_default(
+    command: Command,
+    section: Option<&(&str, &str)>,
+    name: &str,
+    value: toml::Value,
+) -> Result<Command, String> {
+    let setting = match section {
+        Some((section, _)) => format!("{}.{}", section, name),
+        None => name.to_string(),
+    };
+    let long = name.replace('_', "-");
+    let id = command
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(long.as_str()) && long != "config")
+        .map(|arg| arg.get_id().clone())
+        .ok_or_else(|| format!("unknown setting `{}`", setting))?;
+
+    let belongs_in = SECTIONS.iter().find(|(_, group)| {
+        command
+            .get_groups()
+            .any(|g| g.get_id() == group && g.get_args().any(|arg| arg == &id))
+    });
+    if belongs_in.map(|(section, _)| section) != section.map(|(section, _)| section) {
+        return Err(match belongs_in {
+            Some((section, _)) => format!("`{}` belongs in [{}]", setting, section),
+            None => format!("`{}` belongs outside of any section", setting),
+        });
+    }
+
+    let values = match value {
+        toml::Value::Array(values) => values
+            .into_iter()
+            .map(|value| scalar(&setting, value))
+            .collect::<Result<Vec<_>, _>>()?,
+        value => vec![scalar(&setting, value)?],
+    };
+    Ok(command.mut_arg(id, |arg| arg.default_values(values).required(false)))
+}
+
+fn scalar(setting: &str, value: toml::Value) -> Result<String, String> {
+    match value {
+        toml::Value::String(value) => Ok(value),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Float(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        _ => Err(format!(
+            "`{}` should be a string, number, boolean or a list of them",
+            setting
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{Args, CommandFactory, FromArgMatches};
+
+    use super::*;
+
+    #[derive(Parser, Debug)]
+    struct TestCli {
+        #[arg(long)]
+        name: Option<String>,
+
+        #[command(flatten)]
+        server: ServerArgs,
+    }
+
+    /// Named like the real group so it belongs in `[server]`.
+    #[derive(Args, Debug)]
+    struct ServerArgs {
+        #[arg(long, default_value_t = 3000)]
+        port: u16,
+
+        #[arg(long, value_delimiter = ',')]
+        origins: Vec<String>,
+
+        #[arg(long, env = "CONFIG_TEST_LABEL")]
+        label: Option<String>,
+    }
+
+    fn parse(config: &str, args: &[&str]) -> Result<TestCli, String> {
+        let path = std::env::temp_dir().join(format!("config-{}.toml", uuid::Uuid::new_v4()));
+        std::fs::write(&path, config).unwrap();
+        let command = apply_file(TestCli::command(), &path);
+        std::fs::remove_file(&path).unwrap();
+
+        let matches = command?
+            .try_get_matches_from(std::iter::once("test").chain(args.iter().copied()))
+            .map_err(|err| err.to_string())?;
+        TestCli::from_arg_matches(&matches).map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn file_replaces_the_defaults() {
+        let cli = parse(
+            "name = \"file\"\n[server]\nport = 8080\norigins = [\"a\", \"b\"]\n",
+            &[],
+        )
+        .unwrap();
+        assert_eq!(cli.name.as_deref(), Some("file"));
+        assert_eq!(cli.server.port, 8080);
+        assert_eq!(cli.server.origins, ["a", "b"]);
+
+        let cli = parse("", &[]).unwrap();
+        assert_eq!(cli.name, None);
+        assert_eq!(cli.server.port, 3000);
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let cli = parse(
+            "[server]\nport = 8080\norigins = [\"a\"]\n",
+            &["--port", "9000", "--origins", "b,c"],
+        )
+        .unwrap();
+        assert_eq!(cli.server.port, 9000);
+        assert_eq!(cli.server.origins, ["b", "c"]);
+    }
+
+    #[test]
+    fn environment_overrides_the_file_and_flags_override_it() {
+        std::env::set_var("CONFIG_TEST_LABEL", "env");
+        let from_env = parse("[server]\nlabel = \"file\"\n", &[]);
+        let from_flag = parse("[server]\nlabel = \"file\"\n", &["--label", "flag"]);
+        std::env::remove_var("CONFIG_TEST_LABEL");
+
+        assert_eq!(from_env.unwrap().server.label.as_deref(), Some("env"));
+        assert_eq!(from_flag.unwrap().server.label.as_deref(), Some("flag"));
+    }
+
+    #[test]
+    fn settings_have_to_be_in_their_section() {
+        assert_eq!(
+            parse("port = 8080\n", &[]).unwrap_err(),
+            "`port` belongs in [server]"
+        );
+        assert_eq!(
+            parse("[server]\nname = \"x\"\n", &[]).unwrap_err(),
+            "`server.name` belongs outside of any section"
+        );
+        assert_eq!(
+            parse("[log]\nport = 8080\n", &[]).unwrap_err(),
+            "`log.port` belongs in [server]"
+        );
+    }
+
+    #[test]
+    fn unknown_settings_are_rejected() {
+        assert_eq!(
+            parse("[nope]\nport = 8080\n", &[]).unwrap_err(),
+            "unknown section [nope]"
+        );
+        assert_eq!(
+            parse("[server]\nnope = 1\n", &[]).unwrap_err(),
+            "unknown setting `server.nope`"
+        );
+        assert_eq!(
+            parse("config = \"other.toml\"\n", &[]).unwrap_err(),
+            "unknown setting `config`"
+        );
+        assert!(parse("name = \n", &[]).is_err());
+    }
+
+    #[test]
+    fn file_values_are_validated_like_flags() {
+        assert!(parse("[server]\nport = \"not a port\"\n", &[]).is_err());
+        assert_eq!(
+            parse("[server]\nport = { value = 1 }\n", &[]).unwrap_err(),
+            "`server.port` should be a string, number, boolean or a list of them"
+        );
+    }
+}
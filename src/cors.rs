@@ -0,0 +1,348 @@
+//! Cross origin access has two policies. Players embedded on other sites may
+//! fetch playlists, segments and keys, while the management and ingest API is
+//! only open to the origins it is configured for.
+
+use std::task::{Context, Poll};
+
+use anyhow::anyhow;
+use axum::{
+    extract::{MatchedPath, Request},
+    http::{header, HeaderName, HeaderValue, Method},
+};
+use clap::Args;
+use tower::{Layer, Service};
+use tower_http::cors::{AllowOrigin, Cors, CorsLayer};
+
+use crate::api::serve::NEXT_CURSOR_HEADER;
+
+/// The routes players use, they get the playback policy for these methods.
+const PLAYBACK_ROUTES: &[(&str, &[Method])] = &[
+    ("/stream/:streamId", &[Method::GET, Method::HEAD]),
+    ("/stream/:streamId/key/:keyId", &[Method::GET, Method::HEAD]),
+    ("/stream/:streamId/download", &[Method::GET, Method::HEAD]),
+    ("/stream/:streamId/unlock", &[Method::POST]),
+    (
+        "/segment/:streamId/:segmentId",
+        &[Method::GET, Method::HEAD],
+    ),
+    ("/channels/:channelId/live", &[Method::GET, Method::HEAD]),
+];
+
+#[derive(Args, Debug)]
+pub struct CorsArgs {
+    /// Comma separated origins browsers may call the management and ingest API from, e.g. `https://example.com`, `*` allows any. Only same origin requests are allowed when unset
+    #[arg(long, env = "CORS_ALLOWED_ORIGINS", value_delimiter = ',')]
+    pub cors_allowed_origins: Vec<String>,
+
+    /// Comma separated methods allowed on the management and ingest API
+    #[arg(long, env = "CORS_ALLOWED_METHODS", value_delimiter = ',', default_value = "GET,POST,PUT,PATCH,DELETE", value_parser = parse_method)]
+    pub cors_allowed_methods: Vec<Method>,
+
+    /// Comma separated request headers allowed on the management and ingest API
+    #[arg(
+        long,
+        env = "CORS_ALLOWED_HEADERS",
+        value_delimiter = ',',
+        default_value = "authorization,content-type"
+    )]
+    pub cors_allowed_headers: Vec<HeaderName>,
+
+    /// Let browsers send cookies with requests to the management and ingest API, needs a list of origins
+    #[arg(long, env = "CORS_ALLOW_CREDENTIALS")]
+    pub cors_allow_credentials: bool,
+
+    /// Comma separated origins players may fetch streams from, `*` allows any
+    #[arg(
+        long,
+        env = "CORS_PLAYBACK_ORIGINS",
+        value_delimiter = ',',
+        default_value = "*"
+    )]
+    pub cors_playback_origins: Vec<String>,
+
+    /// Comma separated request headers players may send
+    #[arg(
+        long,
+        env = "CORS_PLAYBACK_HEADERS",
+        value_delimiter = ',',
+        default_value = "content-type,range"
+    )]
+    pub cors_playback_headers: Vec<HeaderName>,
+
+    /// Let players send the unlock cookie of password protected streams, needs a list of origins
+    #[arg(long, env = "CORS_PLAYBACK_CREDENTIALS")]
+    pub cors_playback_credentials: bool,
+}
+
+impl CorsArgs {
+    pub fn layer(&self) -> Result<CorsPolicies, anyhow::Error> {
+        let mut playback_methods: Vec<Method> = Vec::new();
+        for method in PLAYBACK_ROUTES.iter().flat_map(|(_, methods)| *methods) {
+            if !playback_methods.contains(method) {
+                playback_methods.push(method.clone());
+            }
+        }
+        Ok(CorsPolicies {
+            playback: policy(
+                &self.cors_playback_origins,
+                playback_methods,
+                self.cors_playback_headers.clone(),
+                self.cors_playback_credentials,
+            )?,
+            // Paging through streams needs the cursor of the next page
+            management: policy(
+                &self.cors_allowed_origins,
+                self.cors_allowed_methods.clone(),
+                self.cors_allowed_headers.clone(),
+                self.cors_allow_credentials,
+            )?
+            .expose_headers([HeaderName::from_static(NEXT_CURSOR_HEADER)]),
+        })
+    }
+}
+
+fn policy(
+    origins: &[String],
+    methods: Vec<Method>,
+    headers: Vec<HeaderName>,
+    credentials: bool,
+) -> Result<CorsLayer, anyhow::Error> {
+    let any_origin = origins.iter().any(|origin| origin == "*");
+    if any_origin && origins.len() > 1 {
+        return Err(anyhow!(
+            "CORS origin `*` can't be combined with other origins"
+        ));
+    }
+    // Browsers refuse credentialed responses that allow any origin
+    if any_origin && credentials {
+        return Err(anyhow!(
+            "CORS credentials need a list of origins instead of `*`"
+        ));
+    }
+
+    let allow_origin = if any_origin {
+        AllowOrigin::any()
+    } else {
+        origins
+            .iter()
+            .map(|origin| parse_origin(origin))
+            .collect::<Result<Vec<_>, _>>()?
+            .into()
+    };
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods(methods)
+        .allow_headers(headers)
+        .allow_credentials(credentials))
+}
+
+/// Origins are compared byte for byte, so catch the usual ways of writing one
+/// that would never match.
+fn parse_origin(origin: &str) -> Result<HeaderValue, anyhow::Error> {
+    let (scheme, host) = origin
+        .split_once("://")
+        .ok_or_else(|| anyhow!("CORS origin {:?} is missing a scheme", origin))?;
+    if !matches!(scheme, "http" | "https") || host.is_empty() || host.contains('/') {
+        return Err(anyhow!(
+            "CORS origin {:?} should look like `https://example.com`, without a path",
+            origin
+        ));
+    }
+    HeaderValue::from_str(origin).map_err(|_| anyhow!("CORS origin {:?} is not valid", origin))
+}
+
+fn parse_method(method: &str) -> Result<Method, String> {
+    Method::from_bytes(method.trim().to_ascii_uppercase().as_bytes())
+        .map_err(|_| format!("{:?} is not a valid method", method))
+}
+
+/// Applies the playback or the management policy depending on the route and
+/// method of a request. Has to be added with `Router::layer` so the route is
+/// known.
+#[derive(Debug, Clone)]
+pub struct CorsPolicies {
+    playback: CorsLayer,
+    management: CorsLayer,
+}
+
+impl<S: Clone> Layer<S> for CorsPolicies {
+    type Service = CorsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        CorsService {
+            playback: self.playback.layer(inner.clone()),
+            management: self.management.layer(inner),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CorsService<S> {
+    playback: Cors<S>,
+    management: Cors<S>,
+}
+
+impl<S> Service<Request> for CorsService<S>
+where
+    Cors<S>: Service<Request>,
+{
+    type Response = <Cors<S> as Service<Request>>::Response;
+    type Error = <Cors<S> as Service<Request>>::Error;
+    type Future = <Cors<S> as Service<Request>>::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self.playback.poll_ready(cx) {
+            Poll::Ready(Ok(())) => self.management.poll_ready(cx),
+            poll => poll,
+        }
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        if is_playback(&req) {
+            self.playback.call(req)
+        } else {
+            self.management.call(req)
+        }
+    }
+}
+
+/// Preflight requests are judged by the method they ask for, so a player
+/// origin can't get a preflight for deleting a stream through its playlist
+/// route.
+fn is_playback(req: &Request) -> bool {
+    let Some(route) = req.extensions().get::<MatchedPath>() else {
+        return false;
+    };
+    let method = if req.method() == Method::OPTIONS {
+        match req
+            .headers()
+            .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|method| Method::from_bytes(method.as_bytes()).ok())
+        {
+            Some(method) => method,
+            None => return false,
+        }
+    } else {
+        req.method().clone()
+    };
+
+    PLAYBACK_ROUTES
+        .iter()
+        .any(|(path, methods)| *path == route.as_str() && methods.contains(&method))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn args() -> CorsArgs {
+        CorsArgs {
+            cors_allowed_origins: vec!["https://admin.example.com".into()],
+            cors_allowed_methods: vec![Method::GET, Method::DELETE],
+            cors_allowed_headers: vec![header::AUTHORIZATION],
+            cors_allow_credentials: false,
+            cors_playback_origins: vec!["*".into()],
+            cors_playback_headers: vec![header::RANGE],
+            cors_playback_credentials: false,
+        }
+    }
+
+    fn app() -> Router {
+        Router::new()
+            .route("/stream/:streamId", get(|| async {}).delete(|| async {}))
+            .route("/streams", get(|| async {}))
+            .layer(args().layer().unwrap())
+    }
+
+    async fn allowed_origin(req: Request) -> Option<HeaderValue> {
+        let response = app().oneshot(req).await.unwrap();
+        response
+            .headers()
+            .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+            .cloned()
+    }
+
+    fn request(method: Method, uri: &str, origin: &str) -> axum::http::request::Builder {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::ORIGIN, origin)
+    }
+
+    fn preflight(uri: &str, origin: &str, method: Method) -> Request {
+        request(Method::OPTIONS, uri, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, method.as_str())
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn players_get_the_playback_policy() {
+        let req = request(Method::GET, "/stream/abc", "https://player.example.com")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(allowed_origin(req).await.unwrap(), "*");
+
+        let req = preflight("/stream/abc", "https://player.example.com", Method::GET);
+        assert_eq!(allowed_origin(req).await.unwrap(), "*");
+    }
+
+    #[tokio::test]
+    async fn management_routes_only_allow_configured_origins() {
+        let req = request(Method::GET, "/streams", "https://player.example.com")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(allowed_origin(req).await, None);
+
+        let req = request(Method::GET, "/streams", "https://admin.example.com")
+            .body(Body::empty())
+            .unwrap();
+        let response = app().oneshot(req).await.unwrap();
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://admin.example.com"
+        );
+        assert_eq!(
+            response.headers()[header::ACCESS_CONTROL_EXPOSE_HEADERS],
+            NEXT_CURSOR_HEADER
+        );
+    }
+
+    #[tokio::test]
+    async fn preflights_are_judged_by_the_requested_method() {
+        let req = preflight("/stream/abc", "https://player.example.com", Method::DELETE);
+        assert_eq!(allowed_origin(req).await, None);
+
+        let req = preflight("/stream/abc", "https://admin.example.com", Method::DELETE);
+        assert_eq!(
+            allowed_origin(req).await.unwrap(),
+            "https://admin.example.com"
+        );
+
+        // Without a requested method there is nothing to judge
+        let req = request(Method::OPTIONS, "/stream/abc", "https://player.example.com")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(allowed_origin(req).await, None);
+    }
+
+    #[test]
+    fn policy_rejects_wildcards_mixed_with_origins_or_credentials() {
+        let origins = ["*".to_string(), "https://example.com".to_string()];
+        assert!(policy(&origins, vec![], vec![], false).is_err());
+        assert!(policy(&origins[..1], vec![], vec![], true).is_err());
+        assert!(policy(&origins[1..], vec![], vec![], true).is_ok());
+    }
+
+    #[test]
+    fn parse_origin_rejects_paths_and_missing_schemes() {
+        assert!(parse_origin("https://example.com").is_ok());
+        assert!(parse_origin("http://localhost:3000").is_ok());
+        assert!(parse_origin("example.com").is_err());
+        assert!(parse_origin("https://example.com/").is_err());
+        assert!(parse_origin("ftp://example.com").is_err());
+    }
+}
@@ -0,0 +1,266 @@
+//! Optional AES-128 encryption of HLS segments. ffmpeg encrypts every segment
+//! with the key its key info file points at, which is swapped for a new key
+//! every `--key-rotation-secs` while a stream is ingested. Keys are kept in the
+//! database and handed to players by the key route, the key files ffmpeg reads
+//! live in `--key-dir`, away from anything that is served.
+
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use clap::Args;
+use sqlx::{Pool, Sqlite};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use crate::hls::{key_uri as tag_key_uri, segment_file_name, Playlist};
+
+const KEY_INFO_FILE_NAME: &str = "keyinfo";
+
+#[derive(Args, Debug)]
+pub struct EncryptionArgs {
+    /// Where the key files ffmpeg encrypts segments with are written, must not be served. Defaults to a directory in the system temp dir
+    #[arg(long, env = "KEY_DIR")]
+    pub key_dir: Option<PathBuf>,
+
+    /// Seconds a stream is encrypted with one key before rotating to a new one, 0 keeps a single key per stream
+    #[arg(long, env = "KEY_ROTATION_SECS", default_value_t = 60)]
+    pub key_rotation_secs: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Keys {
+    dir: PathBuf,
+    rotation: Option<Duration>,
+}
+
+impl Keys {
+    pub async fn from_args(args: &EncryptionArgs) -> Result<Keys, anyhow::Error> {
+        let dir = args
+            .key_dir
+            .clone()
+            .unwrap_or_else(|| std::env::temp_dir().join("can-i-get-a-stream-keys"));
+        tokio::fs::create_dir_all(&dir).await?;
+        tokio::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700)).await?;
+        Ok(Keys {
+            dir,
+            rotation: (args.key_rotation_secs > 0)
+                .then(|| Duration::from_secs(args.key_rotation_secs)),
+        })
+    }
+
+    fn stream_dir(&self, stream_id: &str) -> PathBuf {
+        self.dir.join(stream_id)
+    }
+
+    /// Creates a new key for a stream and points its key info file at it.
+    async fn rotate(&self, db: &Pool<Sqlite>, stream_id: &str) -> Result<(), anyhow::Error> {
+        let key_id = uuid::Uuid::new_v4().to_string();
+        let key: [u8; 16] = rand::random();
+        sqlx::query(
+            r#"INSERT INTO `streamKeys` (`id`, `streamId`, `key`, `createdAt`) VALUES ($1, $2, $3, $4)"#,
+        )
+        .bind(&key_id)
+        .bind(stream_id)
+        .bind(&key[..])
+        .bind(chrono::Utc::now())
+        .execute(db)
+        .await?;
+
+        let dir = self.stream_dir(stream_id);
+        tokio::fs::create_dir_all(&dir).await?;
+        let key_path = dir.join(format!("{}.key", key_id));
+        tokio::fs::write(&key_path, key).await?;
+
+        // ffmpeg rereads the key info file for every segment, so swap it in
+        // whole. The playlist gets the bare key id as the uri, the url is
+        // filled in when it is served. Without an IV line the media sequence
+        // number is used.
+        let key_info = format!("{}\n{}\n", key_id, key_path.to_string_lossy());
+        let tmp_path = dir.join(format!("{}.tmp", KEY_INFO_FILE_NAME));
+        tokio::fs::write(&tmp_path, key_info).await?;
+        tokio::fs::rename(&tmp_path, dir.join(KEY_INFO_FILE_NAME)).await?;
+
+        info!(stream_id, key_id, "Rotated stream key");
+        Ok(())
+    }
+
+    /// Writes the keys a playlist's segments are encrypted with to files ffmpeg
+    /// can read and points the playlist at them. The files are removed when the
+    /// returned [`LocalKeys`] is dropped.
+    pub async fn local_keys(
+        &self,
+        db: &Pool<Sqlite>,
+        stream_id: &str,
+        playlist: Playlist,
+    ) -> Result<(Playlist, LocalKeys), anyhow::Error> {
+        let key_ids: Vec<String> = playlist
+            .segments
+            .iter()
+            .flat_map(|segment| &segment.tags)
+            .filter_map(|tag| tag_key_uri(tag))
+            .map(|uri| segment_file_name(uri).to_string())
+            .collect();
+        if key_ids.is_empty() {
+            return Ok((playlist, LocalKeys(None)));
+        }
+
+        let dir = self.dir.join(uuid::Uuid::new_v4().to_string());
+        tokio::fs::create_dir(&dir).await?;
+        let local_keys = LocalKeys(Some(dir.clone()));
+        for key_id in &key_ids {
+            let key = get_key(db, stream_id, key_id)
+                .await?
+                .ok_or_else(|| anyhow!("Key {} of stream {} not found", key_id, stream_id))?;
+            tokio::fs::write(dir.join(format!("{}.key", key_id)), key).await?;
+        }
+
+        let playlist = playlist.map_key_uris(|uri| {
+            dir.join(format!("{}.key", segment_file_name(uri)))
+                .to_string_lossy()
+                .to_string()
+        });
+        Ok((playlist, local_keys))
+    }
+}
+
+pub async fn get_key(
+    db: &Pool<Sqlite>,
+    stream_id: &str,
+    key_id: &str,
+) -> Result<Option<Vec<u8>>, sqlx::Error> {
+    sqlx::query_scalar(r#"SELECT `key` FROM `streamKeys` WHERE `streamId` = $1 AND `id` = $2"#)
+        .bind(stream_id)
+        .bind(key_id)
+        .fetch_optional(db)
+        .await
+}
+
+/// Gives a clip the keys of the stream its segments were linked from.
+pub async fn copy_keys(db: &Pool<Sqlite>, from: &str, to: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"INSERT INTO `streamKeys` (`id`, `streamId`, `key`, `createdAt`) SELECT `id`, $2, `key`, `createdAt` FROM `streamKeys` WHERE `streamId` = $1"#,
+    )
+    .bind(from)
+    .bind(to)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+pub async fn delete_keys(db: &Pool<Sqlite>, stream_id: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(r#"DELETE FROM `streamKeys` WHERE `streamId` = $1"#)
+        .bind(stream_id)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Key files written for ffmpeg to decrypt segments with, see [`Keys::local_keys`].
+pub struct LocalKeys(Option<PathBuf>);
+
+impl LocalKeys {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_none()
+    }
+}
+
+impl Drop for LocalKeys {
+    fn drop(&mut self) {
+        if let Some(dir) = &self.0 {
+            if let Err(err) = std::fs::remove_dir_all(dir) {
+                warn!(%err, ?dir, "Failed to remove local keys");
+            }
+        }
+    }
+}
+
+/// Keeps the key of a stream being encoded rotating. Dropping it stops the
+/// rotation, [`KeyRotation::finish`] also waits for the key files to be removed.
+pub struct KeyRotation {
+    key_info: PathBuf,
+    stop: CancellationToken,
+    task: Option<JoinHandle<()>>,
+}
+
+impl KeyRotation {
+    /// Creates the first key so the key info file exists before ffmpeg starts.
+    pub async fn start(
+        keys: &Keys,
+        db: &Pool<Sqlite>,
+        stream_id: &str,
+    ) -> Result<KeyRotation, anyhow::Error> {
+        keys.rotate(db, stream_id).await?;
+
+        let key_info = keys.stream_dir(stream_id).join(KEY_INFO_FILE_NAME);
+        let stop = CancellationToken::new();
+        let stopped = stop.clone();
+        let keys = keys.clone();
+        let db = db.clone();
+        let stream_id = stream_id.to_string();
+        let task = tokio::spawn(async move {
+            if let Some(rotation) = keys.rotation {
+                let mut interval = tokio::time::interval(rotation);
+                interval.tick().await;
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => (),
+                        _ = stopped.cancelled() => break,
+                    }
+                    if let Err(err) = keys.rotate(&db, &stream_id).await {
+                        warn!(%err, stream_id, "Failed to rotate stream key, keeping the old one");
+                    }
+                }
+            } else {
+                stopped.cancelled().await;
+            }
+
+            if let Err(err) = tokio::fs::remove_dir_all(keys.stream_dir(&stream_id)).await {
+                warn!(%err, stream_id, "Failed to remove key files");
+            }
+        });
+
+        Ok(KeyRotation {
+            key_info,
+            stop,
+            task: Some(task),
+        })
+    }
+
+    /// The hls muxer options that encrypt segments with the rotating key. The
+    /// flags replace any given earlier.
+    pub fn ffmpeg_args(&self) -> [&std::ffi::OsStr; 4] {
+        [
+            "-hls_key_info_file".as_ref(),
+            self.key_info.as_os_str(),
+            "-hls_flags".as_ref(),
+            "independent_segments+periodic_rekey".as_ref(),
+        ]
+    }
+
+    pub async fn finish(mut self) {
+        self.stop.cancel();
+        if let Some(task) = self.task.take() {
+            if let Err(err) = task.await {
+                error!(%err, "Key rotation panicked");
+            }
+        }
+    }
+}
+
+impl Drop for KeyRotation {
+    fn drop(&mut self) {
+        self.stop.cancel();
+    }
+}
+
+/// The ffmpeg input options that let the hls demuxer read key files from disk.
+pub fn ffmpeg_input_args(local_keys: &LocalKeys) -> &'static [&'static str] {
+    if local_keys.is_empty() {
+        &[]
+    } else {
+        &["-allowed_extensions", "ALL"]
+    }
+}
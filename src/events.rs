@@ -0,0 +1,112 @@
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tracing::trace;
+
+/// Changes to the stream catalog, fanned out to every `/events` subscriber.
+#[derive(Debug, Clone, Serialize)]
+#[serde(
+    tag = "type",
+    rename_all = "kebab-case",
+    rename_all_fields = "camelCase"
+)]
+pub enum StreamEvent {
+    StreamCreated {
+        stream_id: String,
+    },
+    WentLive {
+        stream_id: String,
+    },
+    Ended {
+        stream_id: String,
+        status: String,
+    },
+    /// Moved to the trash, or purged without going through it
+    Deleted {
+        stream_id: String,
+    },
+    Restored {
+        stream_id: String,
+    },
+    Updated {
+        stream_id: String,
+    },
+}
+
+impl StreamEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StreamEvent::StreamCreated { .. } => "stream-created",
+            StreamEvent::WentLive { .. } => "went-live",
+            StreamEvent::Ended { .. } => "ended",
+            StreamEvent::Deleted { .. } => "deleted",
+            StreamEvent::Restored { .. } => "restored",
+            StreamEvent::Updated { .. } => "updated",
+        }
+    }
+
+    pub fn stream_id(&self) -> &str {
+        match self {
+            StreamEvent::StreamCreated { stream_id }
+            | StreamEvent::WentLive { stream_id }
+            | StreamEvent::Ended { stream_id, .. }
+            | StreamEvent::Deleted { stream_id }
+            | StreamEvent::Restored { stream_id }
+            | StreamEvent::Updated { stream_id } => stream_id,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EventBus(broadcast::Sender<StreamEvent>);
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self(sender)
+    }
+
+    pub fn publish(&self, event: StreamEvent) {
+        // An error only means nobody is listening right now
+        if self.0.send(event).is_err() {
+            trace!("No event subscribers");
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<StreamEvent> {
+        self.0.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializes_with_its_name_as_the_type() {
+        let event = StreamEvent::Ended {
+            stream_id: "abc".to_string(),
+            status: "finished".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({ "type": event.name(), "streamId": "abc", "status": "finished" })
+        );
+    }
+
+    #[tokio::test]
+    async fn subscribers_only_see_events_published_after_subscribing() {
+        let bus = EventBus::new(4);
+        bus.publish(StreamEvent::Deleted {
+            stream_id: "before".to_string(),
+        });
+        let mut events = bus.subscribe();
+        bus.publish(StreamEvent::WentLive {
+            stream_id: "after".to_string(),
+        });
+        match events.recv().await.unwrap() {
+            StreamEvent::WentLive { stream_id } => assert_eq!(stream_id, "after"),
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert!(events.try_recv().is_err());
+    }
+}
@@ -0,0 +1,255 @@
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+};
+
+use sqlx::{Pool, Sqlite};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader},
+};
+use tracing::{error, info, trace, warn};
+
+use crate::utils::ResourceDir;
+
+/// Once a log grows past this it is moved to `{log}.1`, replacing the previous
+/// one, so each stream keeps at most twice this much.
+const MAX_LOG_BYTES: u64 = 1024 * 1024;
+/// How many of the last stderr lines are kept as the failure reason.
+const FAILURE_REASON_LINES: usize = 20;
+/// Longer lines are split, so a stderr without line breaks can't grow a line
+/// without bound.
+const MAX_LINE_BYTES: usize = 4096;
+/// The directory in the resource dir logs were kept in before they got their
+/// own, where the segment route served them.
+pub const LEGACY_LOGS_DIR: &str = "logs";
+
+/// Reads up to and including the next `\n` or `\r` into `buff`, at most
+/// [`MAX_LINE_BYTES`]. `-stats` redraws its progress line with carriage returns
+/// and only ends it once encoding is done.
+async fn read_line<R>(reader: &mut R, buff: &mut Vec<u8>) -> std::io::Result<usize>
+where
+    R: AsyncBufRead + Unpin,
+{
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return Ok(buff.len());
+        }
+        let available = &available[..available.len().min(MAX_LINE_BYTES - buff.len())];
+        let (used, ended) = match available.iter().position(|b| *b == b'\n' || *b == b'\r') {
+            Some(end) => (end + 1, true),
+            None => (
+                available.len(),
+                buff.len() + available.len() == MAX_LINE_BYTES,
+            ),
+        };
+        buff.extend_from_slice(&available[..used]);
+        reader.consume(used);
+        if ended {
+            return Ok(buff.len());
+        }
+    }
+}
+
+/// Copies ffmpeg's stderr into the stream's log until the process closes it,
+/// returning the last lines written.
+pub async fn capture<R>(stderr: R, log_path: PathBuf) -> Vec<String>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut reader = BufReader::new(stderr);
+    let mut log = match RollingLog::open(log_path).await {
+        Ok(log) => Some(log),
+        Err(err) => {
+            warn!(%err, "Failed to open ffmpeg log, only keeping the tail");
+            None
+        }
+    };
+
+    let mut tail = VecDeque::with_capacity(FAILURE_REASON_LINES);
+    let mut buff = Vec::new();
+    let mut redrawn = false;
+    loop {
+        buff.clear();
+        match read_line(&mut reader, &mut buff).await {
+            Ok(0) => break,
+            Ok(_) => (),
+            Err(err) => {
+                error!(%err, "Failed to read ffmpeg stderr");
+                break;
+            }
+        }
+
+        if let Some(rolling_log) = &mut log {
+            if let Err(err) = rolling_log.write(&buff).await {
+                warn!(%err, "Failed to write ffmpeg log, only keeping the tail");
+                log = None;
+            }
+        }
+
+        // `-stats` redraws its progress line with carriage returns, only the
+        // latest one is worth keeping
+        let replaces_last = redrawn;
+        redrawn = buff.ends_with(b"\r");
+        let line = String::from_utf8_lossy(&buff);
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+        trace!(line, "ffmpeg");
+        if replaces_last {
+            tail.pop_back();
+        }
+        if tail.len() == FAILURE_REASON_LINES {
+            tail.pop_front();
+        }
+        tail.push_back(line.to_string());
+    }
+
+    if let Some(mut log) = log {
+        if let Err(err) = log.file.flush().await {
+            warn!(%err, "Failed to flush ffmpeg log");
+        }
+    }
+    tail.into()
+}
+
+pub async fn save_failure_reason(db: &Pool<Sqlite>, stream_id: &str, reason: &str) {
+    if let Err(err) = sqlx::query(r#"UPDATE `streams` SET `failureReason` = $1 WHERE `id` = $2"#)
+        .bind(reason)
+        .bind(stream_id)
+        .execute(db)
+        .await
+    {
+        error!(%err, "Failed to save failure reason");
+    }
+}
+
+/// The rotated log followed by the current one, so lines stay in order.
+pub async fn read(log_path: &Path) -> String {
+    let mut log = tokio::fs::read_to_string(rotated_path(log_path))
+        .await
+        .unwrap_or_default();
+    log.push_str(
+        &tokio::fs::read_to_string(log_path)
+            .await
+            .unwrap_or_default(),
+    );
+    log
+}
+
+pub async fn remove(log_path: &Path) {
+    for path in [log_path.to_path_buf(), rotated_path(log_path)] {
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => (),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+            Err(err) => warn!(%err, ?path, "Failed to remove ffmpeg log"),
+        }
+    }
+}
+
+/// Moves logs an earlier version kept inside the resource dir over to the logs
+/// dir.
+pub async fn move_legacy_logs(resource_dir: &ResourceDir) -> std::io::Result<()> {
+    let legacy_dir = resource_dir.root().join(LEGACY_LOGS_DIR);
+    let mut entries = match tokio::fs::read_dir(&legacy_dir).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let to = resource_dir.logs_dir().join(entry.file_name());
+        // The logs dir may be on another file system
+        if tokio::fs::rename(entry.path(), &to).await.is_err() {
+            tokio::fs::copy(entry.path(), &to).await?;
+            tokio::fs::remove_file(entry.path()).await?;
+        }
+    }
+    tokio::fs::remove_dir(&legacy_dir).await?;
+    info!(?legacy_dir, "Moved ffmpeg logs out of the resource dir");
+    Ok(())
+}
+
+fn rotated_path(log_path: &Path) -> PathBuf {
+    let mut path = log_path.as_os_str().to_owned();
+    path.push(".1");
+    path.into()
+}
+
+struct RollingLog {
+    path: PathBuf,
+    file: File,
+    written: u64,
+}
+
+impl RollingLog {
+    async fn open(path: PathBuf) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        let written = file.metadata().await?.len();
+        Ok(Self {
+            path,
+            file,
+            written,
+        })
+    }
+
+    async fn write(&mut self, buff: &[u8]) -> std::io::Result<()> {
+        if self.written + buff.len() as u64 > MAX_LOG_BYTES {
+            self.file.flush().await?;
+            tokio::fs::rename(&self.path, rotated_path(&self.path)).await?;
+            self.file = File::create(&self.path).await?;
+            self.written = 0;
+        }
+        self.file.write_all(buff).await?;
+        self.written += buff.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn lines(stderr: &[u8]) -> Vec<String> {
+        let mut reader = BufReader::new(stderr);
+        let mut lines = Vec::new();
+        let mut buff = Vec::new();
+        while read_line(&mut reader, &mut buff).await.unwrap() > 0 {
+            lines.push(String::from_utf8(buff.clone()).unwrap());
+            buff.clear();
+        }
+        lines
+    }
+
+    #[tokio::test]
+    async fn progress_redraws_are_lines() {
+        assert_eq!(
+            lines(b"Input #0\nframe=1\rframe=2\rframe=3\nend").await,
+            ["Input #0\n", "frame=1\r", "frame=2\r", "frame=3\n", "end"]
+        );
+    }
+
+    #[tokio::test]
+    async fn long_lines_are_split() {
+        let stderr = vec![b'x'; MAX_LINE_BYTES * 2 + 1];
+        let lengths: Vec<usize> = lines(&stderr).await.iter().map(String::len).collect();
+        assert_eq!(lengths, [MAX_LINE_BYTES, MAX_LINE_BYTES, 1]);
+    }
+
+    #[tokio::test]
+    async fn capture_keeps_the_last_progress_line() {
+        let log_path = std::env::temp_dir().join(format!("{}.log", uuid::Uuid::new_v4()));
+        let tail = capture(
+            &b"frame=1\rframe=2\r\nError opening output\n"[..],
+            log_path.clone(),
+        )
+        .await;
+        assert_eq!(tail, ["frame=2", "Error opening output"]);
+        let _ = tokio::fs::remove_file(&log_path).await;
+    }
+}
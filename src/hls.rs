@@ -0,0 +1,370 @@
+//! Just enough of an m3u8 media playlist parser to slice and rewrite the
+//! playlists ffmpeg writes for us.
+
+#[derive(Debug, Clone)]
+pub struct Playlist {
+    /// Playlist level tags such as `#EXT-X-TARGETDURATION`
+    pub header: Vec<String>,
+    pub segments: Vec<Segment>,
+    /// Whether the playlist has an `#EXT-X-ENDLIST` tag
+    pub ended: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub duration: f64,
+    /// Every tag that applies to this segment, including the `#EXTINF`
+    pub tags: Vec<String>,
+    pub uri: String,
+}
+
+impl Playlist {
+    pub fn parse(text: &str) -> Playlist {
+        let mut header = Vec::new();
+        let mut segments = Vec::new();
+        let mut ended = false;
+
+        let mut pending_tags: Vec<String> = Vec::new();
+        let mut pending_duration = 0.0;
+
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            if line == "#EXT-X-ENDLIST" {
+                ended = true;
+            } else if let Some(duration) = line.strip_prefix("#EXTINF:") {
+                pending_duration = duration
+                    .split(',')
+                    .next()
+                    .and_then(|duration| duration.parse().ok())
+                    .unwrap_or(0.0);
+                pending_tags.push(line.to_string());
+            } else if line.starts_with('#') {
+                if segments.is_empty() && pending_tags.is_empty() && !is_segment_tag(line) {
+                    header.push(line.to_string());
+                } else {
+                    pending_tags.push(line.to_string());
+                }
+            } else {
+                segments.push(Segment {
+                    duration: pending_duration,
+                    tags: std::mem::take(&mut pending_tags),
+                    uri: line.to_string(),
+                });
+                pending_duration = 0.0;
+            }
+        }
+
+        Playlist {
+            header,
+            segments,
+            ended,
+        }
+    }
+
+    pub fn duration(&self) -> f64 {
+        self.segments.iter().map(|segment| segment.duration).sum()
+    }
+
+    /// The segments overlapping the `start..end` time range along with the
+    /// offset of the first one from the start of the playlist. The first
+    /// segment carries the `#EXT-X-KEY` tag in effect for it, so the range can
+    /// be played on its own.
+    pub fn segments_between(&self, start: f64, end: f64) -> (f64, Vec<Segment>) {
+        let mut offset = 0.0;
+        let mut first_offset = None;
+        let mut segments: Vec<Segment> = Vec::new();
+        let mut key_tag = None;
+
+        for segment in &self.segments {
+            if let Some(tag) = segment.tags.iter().rev().find(|tag| is_key_tag(tag)) {
+                key_tag = Some(tag);
+            }
+            let segment_end = offset + segment.duration;
+            if segment_end > start && offset < end {
+                let mut segment = segment.clone();
+                if segments.is_empty() && !segment.tags.iter().any(|tag| is_key_tag(tag)) {
+                    if let Some(tag) = key_tag {
+                        segment.tags.insert(0, tag.clone());
+                    }
+                }
+                first_offset.get_or_insert(offset);
+                segments.push(segment);
+            }
+            offset = segment_end;
+        }
+
+        (first_offset.unwrap_or(0.0), segments)
+    }
+
+    pub fn map_uris(mut self, f: impl Fn(&str) -> String) -> Playlist {
+        for segment in &mut self.segments {
+            segment.uri = f(&segment.uri);
+        }
+        self
+    }
+
+    /// Rewrites the `URI` of every `#EXT-X-KEY` tag.
+    pub fn map_key_uris(mut self, f: impl Fn(&str) -> String) -> Playlist {
+        for tag in self
+            .segments
+            .iter_mut()
+            .flat_map(|segment| &mut segment.tags)
+        {
+            if let Some(uri) = key_uri(tag) {
+                *tag = with_key_uri(tag, &f(uri));
+            }
+        }
+        self
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for line in &self.header {
+            out.push_str(line);
+            out.push('\n');
+        }
+        for segment in &self.segments {
+            for tag in &segment.tags {
+                out.push_str(tag);
+                out.push('\n');
+            }
+            out.push_str(&segment.uri);
+            out.push('\n');
+        }
+        if self.ended {
+            out.push_str("#EXT-X-ENDLIST\n");
+        }
+        out
+    }
+}
+
+/// Rewrites the segment and key uris of a playlist, leaving the rest of the
+/// text untouched.
+pub fn rewrite_uris(
+    text: &str,
+    segment: impl Fn(&str) -> String,
+    key: impl Fn(&str) -> String,
+) -> String {
+    let mut out = String::with_capacity(text.len());
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if let Some(uri) = key_uri(trimmed) {
+            out.push_str(&with_key_uri(trimmed, &key(uri)));
+        } else if !trimmed.is_empty() && !trimmed.starts_with('#') {
+            out.push_str(&segment(trimmed));
+        } else {
+            out.push_str(line);
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// The `URI` attribute of an `#EXT-X-KEY` tag.
+pub fn key_uri(tag: &str) -> Option<&str> {
+    let (start, end) = key_uri_range(tag)?;
+    Some(&tag[start..end])
+}
+
+fn with_key_uri(tag: &str, uri: &str) -> String {
+    match key_uri_range(tag) {
+        Some((start, end)) => format!("{}{}{}", &tag[..start], uri, &tag[end..]),
+        None => tag.to_string(),
+    }
+}
+
+fn key_uri_range(tag: &str) -> Option<(usize, usize)> {
+    if !is_key_tag(tag) {
+        return None;
+    }
+    let start = tag.find("URI=\"")? + "URI=\"".len();
+    let end = start + tag[start..].find('"')?;
+    Some((start, end))
+}
+
+fn is_key_tag(line: &str) -> bool {
+    line.starts_with("#EXT-X-KEY:")
+}
+
+/// The file a segment uri points at, ignoring the base url and any query.
+pub fn segment_file_name(uri: &str) -> &str {
+    let path = uri.split(['?', '#']).next().unwrap_or(uri);
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+fn is_segment_tag(line: &str) -> bool {
+    [
+        "#EXT-X-DISCONTINUITY",
+        "#EXT-X-PROGRAM-DATE-TIME",
+        "#EXT-X-BYTERANGE",
+        "#EXT-X-KEY",
+        "#EXT-X-MAP",
+    ]
+    .iter()
+    .any(|tag| line.starts_with(tag))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAYLIST: &str = "#EXTM3U
+#EXT-X-VERSION:3
+#EXT-X-TARGETDURATION:4
+#EXT-X-MEDIA-SEQUENCE:0
+#EXTINF:4.000000,
+000.ts
+#EXT-X-DISCONTINUITY
+#EXTINF:2.500000,
+001.ts
+#EXTINF:3.5,
+002.ts
+#EXT-X-ENDLIST
+";
+
+    #[test]
+    fn parse_splits_header_and_segments() {
+        let playlist = Playlist::parse(PLAYLIST);
+        assert_eq!(
+            playlist.header,
+            [
+                "#EXTM3U",
+                "#EXT-X-VERSION:3",
+                "#EXT-X-TARGETDURATION:4",
+                "#EXT-X-MEDIA-SEQUENCE:0"
+            ]
+        );
+        let uris: Vec<&str> = playlist.segments.iter().map(|s| s.uri.as_str()).collect();
+        assert_eq!(uris, ["000.ts", "001.ts", "002.ts"]);
+        assert_eq!(
+            playlist.segments[1].tags,
+            ["#EXT-X-DISCONTINUITY", "#EXTINF:2.500000,"]
+        );
+        assert_eq!(playlist.segments[1].duration, 2.5);
+        assert!(playlist.ended);
+    }
+
+    #[test]
+    fn parse_keeps_a_live_playlist_open() {
+        let playlist = Playlist::parse("#EXTM3U\n#EXTINF:4,\n000.ts\n");
+        assert!(!playlist.ended);
+        assert_eq!(playlist.segments.len(), 1);
+    }
+
+    #[test]
+    fn render_round_trips() {
+        assert_eq!(Playlist::parse(PLAYLIST).render(), PLAYLIST);
+    }
+
+    #[test]
+    fn map_uris_rewrites_segments_only() {
+        let rendered = Playlist::parse(PLAYLIST)
+            .map_uris(|uri| format!("/media/{}", uri))
+            .render();
+        assert!(rendered.contains("\n/media/001.ts\n"));
+        assert!(rendered.starts_with("#EXTM3U\n#EXT-X-VERSION:3\n"));
+    }
+
+    #[test]
+    fn segment_file_name_drops_base_and_query() {
+        assert_eq!(segment_file_name("000.ts"), "000.ts");
+        assert_eq!(
+            segment_file_name("https://cdn.example.com/segment/abc/001.ts?token=x"),
+            "001.ts"
+        );
+        assert_eq!(segment_file_name("../segment/abc/002.ts#t"), "002.ts");
+    }
+
+    #[test]
+    fn segments_between_returns_the_overlapping_segments() {
+        let playlist = Playlist::parse(PLAYLIST);
+        let (offset, segments) = playlist.segments_between(5.0, 6.0);
+        assert_eq!(offset, 4.0);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].uri, "001.ts");
+
+        let (offset, segments) = playlist.segments_between(3.0, 7.0);
+        assert_eq!(offset, 0.0);
+        let uris: Vec<&str> = segments.iter().map(|s| s.uri.as_str()).collect();
+        assert_eq!(uris, ["000.ts", "001.ts", "002.ts"]);
+    }
+
+    #[test]
+    fn segments_between_is_empty_past_the_end() {
+        let (offset, segments) = Playlist::parse(PLAYLIST).segments_between(20.0, 30.0);
+        assert_eq!(offset, 0.0);
+        assert!(segments.is_empty());
+    }
+
+    const ENCRYPTED: &str = "#EXTM3U
+#EXT-X-TARGETDURATION:4
+#EXT-X-KEY:METHOD=AES-128,URI=\"key-0\",IV=0x01
+#EXTINF:4,
+000.ts
+#EXTINF:4,
+001.ts
+#EXT-X-KEY:METHOD=AES-128,URI=\"key-1\",IV=0x02
+#EXTINF:4,
+002.ts
+";
+
+    #[test]
+    fn segments_between_carries_the_key_in_effect() {
+        let playlist = Playlist::parse(ENCRYPTED);
+        let (_, segments) = playlist.segments_between(4.0, 8.0);
+        assert_eq!(segments[0].uri, "001.ts");
+        assert_eq!(
+            segments[0].tags,
+            [
+                "#EXT-X-KEY:METHOD=AES-128,URI=\"key-0\",IV=0x01",
+                "#EXTINF:4,"
+            ]
+        );
+
+        // The first segment already has its own key tag
+        let (_, segments) = playlist.segments_between(8.0, 12.0);
+        assert_eq!(segments[0].tags.len(), 2);
+        assert_eq!(key_uri(&segments[0].tags[0]), Some("key-1"));
+    }
+
+    #[test]
+    fn map_key_uris_rewrites_only_the_uri_attribute() {
+        let rendered = Playlist::parse(ENCRYPTED)
+            .map_key_uris(|uri| format!("/keys/{}", uri))
+            .render();
+        assert!(rendered.contains("#EXT-X-KEY:METHOD=AES-128,URI=\"/keys/key-0\",IV=0x01\n"));
+        assert!(rendered.contains("#EXT-X-KEY:METHOD=AES-128,URI=\"/keys/key-1\",IV=0x02\n"));
+        assert_eq!(key_uri("#EXT-X-MAP:URI=\"init.mp4\""), None);
+    }
+
+    #[test]
+    fn rewrite_uris_only_touches_segments_and_keys() {
+        let rewritten = rewrite_uris(
+            ENCRYPTED,
+            |uri| format!("seg/{}", uri),
+            |uri| format!("key/{}", uri),
+        );
+        assert_eq!(
+            rewritten,
+            "#EXTM3U
+#EXT-X-TARGETDURATION:4
+#EXT-X-KEY:METHOD=AES-128,URI=\"key/key-0\",IV=0x01
+#EXTINF:4,
+seg/000.ts
+#EXTINF:4,
+seg/001.ts
+#EXT-X-KEY:METHOD=AES-128,URI=\"key/key-1\",IV=0x02
+#EXTINF:4,
+seg/002.ts
+"
+        );
+    }
+
+    #[test]
+    fn rewrite_uris_leaves_other_tags_alone() {
+        let text = "#EXTM3U\n#EXT-X-MAP:URI=\"init.mp4\"\n\n#EXT-X-ENDLIST\n";
+        assert_eq!(
+            rewrite_uris(text, |_| unreachable!(), |_| unreachable!()),
+            text
+        );
+    }
+}
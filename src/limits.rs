@@ -0,0 +1,49 @@
+use std::sync::Arc;
+
+use clap::Args;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::utils::parse_size;
+
+#[derive(Args, Debug)]
+pub struct LimitsArgs {
+    /// Reject uploads larger than this, e.g. `500M` or `4G`
+    #[arg(long, env = "MAX_UPLOAD_SIZE", value_parser = parse_size)]
+    pub max_upload_size: Option<u64>,
+
+    /// How many uploads and live ingests may run at the same time
+    #[arg(long, env = "MAX_CONCURRENT_INGESTS", value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_concurrent_ingests: Option<u32>,
+}
+
+/// Limits on ingest, unset limits are not enforced.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub max_upload_bytes: Option<u64>,
+    ingests: Option<Arc<Semaphore>>,
+}
+
+impl Limits {
+    pub fn from_args(args: &LimitsArgs) -> Limits {
+        Limits {
+            max_upload_bytes: args.max_upload_size,
+            ingests: args
+                .max_concurrent_ingests
+                .map(|max| Arc::new(Semaphore::new(max as usize))),
+        }
+    }
+
+    /// Claims a slot for an ingest, held until the permit is dropped. `None`
+    /// when every slot is taken.
+    pub fn start_ingest(&self) -> Option<IngestPermit> {
+        let permit = match &self.ingests {
+            Some(ingests) => Some(ingests.clone().try_acquire_owned().ok()?),
+            None => None,
+        };
+        Some(IngestPermit { _permit: permit })
+    }
+}
+
+pub struct IngestPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+}
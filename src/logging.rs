@@ -0,0 +1,120 @@
+use std::path::PathBuf;
+
+use axum::http::Request;
+use clap::{Args, ValueEnum};
+use opentelemetry::{trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{runtime, trace::TracerProvider, Resource};
+use tracing::{error, Span};
+use tracing_subscriber::{
+    fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer, Registry,
+};
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum LogFormat {
+    /// Multi line, human readable output with timestamps
+    Pretty,
+    /// One line per event without timestamps
+    Compact,
+    /// One JSON object per event with timestamps
+    Json,
+}
+
+#[derive(Args, Debug)]
+pub struct LogArgs {
+    #[arg(long, env = "LOG_FORMAT", value_enum, default_value_t = LogFormat::Compact)]
+    pub log_format: LogFormat,
+
+    /// Comma separated `target=level` directives, e.g. `info,sqlx=warn,can_i_get_a_stream::api=debug`
+    #[arg(long, env = "RUST_LOG", default_value = "debug")]
+    pub log_filter: String,
+
+    /// Base url of an OTLP/HTTP collector, spans are exported to `{endpoint}/v1/traces`
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+
+    #[arg(long, env = "OTEL_SERVICE_NAME", default_value = "can-i-get-a-stream")]
+    pub otlp_service_name: String,
+
+    /// Where ffmpeg's output for each stream is kept, must not be inside the resource dir. Defaults to a directory in the system temp dir
+    #[arg(long, env = "FFMPEG_LOG_DIR")]
+    pub ffmpeg_log_dir: Option<PathBuf>,
+}
+
+/// Flushes exported spans when dropped.
+pub struct LogGuard(Option<TracerProvider>);
+
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.0.take() {
+            if let Err(err) = provider.shutdown() {
+                error!(%err, "Failed to flush traces");
+            }
+        }
+    }
+}
+
+pub fn init_logger(args: &LogArgs) -> LogGuard {
+    let env_filter = || {
+        EnvFilter::builder()
+            .parse(&args.log_filter)
+            .expect("Failed to create env filter invalid log filter")
+    };
+
+    let fmt_layer = match args.log_format {
+        LogFormat::Pretty => fmt::layer().pretty().boxed(),
+        LogFormat::Compact => fmt::layer().compact().without_time().boxed(),
+        LogFormat::Json => fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .boxed(),
+    };
+
+    let mut layers = vec![
+        fmt_layer.with_filter(env_filter()).boxed(),
+        crate::metrics::sqlite_query_layer().boxed(),
+    ];
+
+    let provider = args.otlp_endpoint.as_ref().map(|endpoint| {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+            .build()
+            .expect("Failed to create OTLP exporter");
+        TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_resource(Resource::new([KeyValue::new(
+                "service.name",
+                args.otlp_service_name.clone(),
+            )]))
+            .build()
+    });
+    if let Some(provider) = &provider {
+        let tracer = provider.tracer("can-i-get-a-stream");
+        layers.push(
+            tracing_opentelemetry::layer()
+                .with_tracer(tracer)
+                .with_filter(env_filter())
+                .boxed(),
+        );
+    }
+
+    Registry::default()
+        .with(layers)
+        .try_init()
+        .expect("Failed to initialize tracing");
+
+    LogGuard(provider)
+}
+
+/// The span every request is traced in. Only the path of the uri is recorded,
+/// the query can carry a stream password or a playback token.
+pub fn request_span<B>(req: &Request<B>) -> Span {
+    tracing::debug_span!(
+        "request",
+        method = %req.method(),
+        uri = %req.uri().path(),
+        version = ?req.version(),
+    )
+}
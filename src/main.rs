@@ -1,57 +1,240 @@
-use std::{path::PathBuf, process::exit};
+use std::{net::SocketAddr, path::PathBuf, process::exit};
 
 use axum::{
-    http::{request::Parts, HeaderValue},
-    routing::{any, delete, get, post},
+    extract::FromRef,
+    routing::{delete, get, patch, post, put},
     Router,
 };
 use clap::Parser;
+use sqlx::{Pool, Sqlite};
 use tokio::fs::{self};
-use tower_http::cors::{AllowOrigin, CorsLayer};
 use tower_http::trace::TraceLayer;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 mod api;
+mod auth;
+mod config;
+mod cors;
+mod encryption;
+mod events;
+mod ffmpeg_log;
+mod hls;
+mod limits;
+mod logging;
+mod metrics;
+mod playback;
+mod reconcile;
+mod retention;
+mod s3;
+mod server;
+mod shutdown;
+mod storage;
+mod tls;
+mod transcode;
 mod utils;
+mod viewers;
+mod webhooks;
 
 #[derive(Parser, Debug)]
 struct CliArgs {
     #[arg(short, long)]
     rescource_dir: PathBuf,
+
+    /// Bearer token required by admin endpoints, they are disabled without one
+    #[arg(long, env = "ADMIN_TOKEN")]
+    admin_token: Option<String>,
+
+    /// What startup reconciliation does with streams and directories a crash left behind
+    #[arg(long, env = "ORPHAN_POLICY", value_enum, default_value_t = reconcile::OrphanPolicy::Repair)]
+    orphan_policy: reconcile::OrphanPolicy,
+
+    #[command(flatten)]
+    server: server::ServerArgs,
+
+    #[command(flatten)]
+    retention: retention::RetentionPolicy,
+
+    #[command(flatten)]
+    storage: storage::StorageArgs,
+
+    #[command(flatten)]
+    transcode: transcode::TranscodeArgs,
+
+    #[command(flatten)]
+    limits: limits::LimitsArgs,
+
+    #[command(flatten)]
+    tls: tls::TlsArgs,
+
+    #[command(flatten)]
+    cors: cors::CorsArgs,
+
+    #[command(flatten)]
+    playback: playback::PlaybackArgs,
+
+    #[command(flatten)]
+    encryption: encryption::EncryptionArgs,
+
+    #[command(flatten)]
+    log: logging::LogArgs,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, FromRef)]
 struct AppState {
-    resource_dir: PathBuf,
+    db: Pool<Sqlite>,
+    resource_dir: utils::ResourceDir,
+    storage: storage::SharedStorage,
+    events: events::EventBus,
+    viewers: viewers::ViewerTracker,
+    admin_token: auth::AdminToken,
+    playback: playback::PlaybackSigner,
+    keys: encryption::Keys,
+    public_url: server::PublicUrl,
+    transcode: transcode::TranscodeArgs,
+    limits: limits::Limits,
+    shutdown: shutdown::Shutdown,
+    retention: retention::RetentionPolicy,
 }
 
 #[tokio::main]
 async fn main() {
-    utils::init_logger();
-    let args = CliArgs::parse();
+    let args: CliArgs = config::parse();
+    let _log_guard = logging::init_logger(&args.log);
 
-    let socket_addr = match utils::get_socket_addr() {
-        Ok(host_and_port) => host_and_port,
+    let socket_addr = args.server.socket_addr();
+    let tls = match tls::Tls::from_args(&args.tls) {
+        Ok(tls) => tls,
         Err(err) => {
-            error!(?err, "Error getting host and port {}", err);
+            error!(%err, "Invalid TLS configuration");
             exit(1);
         }
     };
-    let app_state = AppState {
-        resource_dir: args.rescource_dir.clone(),
+    let cors = match args.cors.layer() {
+        Ok(cors) => cors,
+        Err(err) => {
+            error!(%err, "Invalid CORS configuration");
+            exit(1);
+        }
     };
-
-    fs::create_dir_all(&app_state.resource_dir)
+    fs::create_dir_all(&args.rescource_dir)
         .await
         .expect("The rescource directory should be created.");
+    let logs_dir = args
+        .log
+        .ffmpeg_log_dir
+        .clone()
+        .unwrap_or_else(|| std::env::temp_dir().join("can-i-get-a-stream-logs"));
+    fs::create_dir_all(&logs_dir)
+        .await
+        .expect("The logs directory should be created.");
+    // Everything in the resource dir can be fetched through the segment route
+    match (
+        fs::canonicalize(&args.rescource_dir).await,
+        fs::canonicalize(&logs_dir).await,
+    ) {
+        (Ok(rescource_dir), Ok(logs_dir)) if logs_dir.starts_with(&rescource_dir) => {
+            error!(
+                ?logs_dir,
+                "The ffmpeg log directory must not be inside the resource directory"
+            );
+            exit(1);
+        }
+        (Ok(_), Ok(_)) => (),
+        (Err(err), _) | (_, Err(err)) => {
+            error!(%err, "Failed to resolve the resource and log directories");
+            exit(1);
+        }
+    }
+    let resource_dir = utils::ResourceDir::new(args.rescource_dir.clone(), logs_dir);
+    if let Err(err) = ffmpeg_log::move_legacy_logs(&resource_dir).await {
+        error!(%err, "Failed to move ffmpeg logs out of the resource directory");
+        exit(1);
+    }
+    let storage = match storage::from_args(&args.storage, &resource_dir) {
+        Ok(storage) => storage,
+        Err(err) => {
+            error!(%err, "Invalid storage configuration");
+            exit(1);
+        }
+    };
+    let public_url = match server::PublicUrl::from_args(&args.server) {
+        Ok(public_url) => public_url,
+        Err(err) => {
+            error!(%err, "Invalid public base url");
+            exit(1);
+        }
+    };
+    let keys = match encryption::Keys::from_args(&args.encryption).await {
+        Ok(keys) => keys,
+        Err(err) => {
+            error!(%err, "Failed to create the key directory");
+            exit(1);
+        }
+    };
 
     let mut db_path = args.rescource_dir.clone();
     db_path.push("db");
     let db_pool = utils::get_db(&db_path).await.expect("Failed to get db");
 
+    let app_state = AppState {
+        db: db_pool,
+        resource_dir,
+        storage,
+        events: events::EventBus::new(256),
+        viewers: viewers::ViewerTracker::default(),
+        admin_token: auth::AdminToken(args.admin_token.map(Into::into)),
+        playback: playback::PlaybackSigner::from_args(&args.playback),
+        keys,
+        public_url,
+        transcode: args.transcode.clone(),
+        limits: limits::Limits::from_args(&args.limits),
+        shutdown: shutdown::Shutdown::default(),
+        retention: args.retention.clone(),
+    };
+
+    let shutdown = app_state.shutdown.clone();
+    let db = app_state.db.clone();
+    let events = app_state.events.clone();
+    tokio::spawn(shutdown.clone().wait_for_signal());
+    app_state.viewers.spawn_sweeper();
+    webhooks::spawn_dispatcher(app_state.db.clone(), &app_state.events);
+    if let Err(err) = reconcile::reconcile(
+        &app_state.db,
+        &app_state.resource_dir,
+        &app_state.storage,
+        &app_state.events,
+        args.orphan_policy,
+    )
+    .await
+    {
+        error!(%err, "Failed to reconcile streams");
+    }
+    retention::spawn_enforcer(
+        app_state.db.clone(),
+        app_state.resource_dir.clone(),
+        app_state.storage.clone(),
+        app_state.events.clone(),
+        app_state.retention.clone(),
+        shutdown.clone(),
+    );
+
     let app = Router::new()
         .route("/stream/:streamId", get(api::serve::stream))
         .route("/stream/:streamId", delete(api::serve::delete_stream))
+        .route("/stream/:streamId", patch(api::serve::update_stream))
+        .route("/stream/:streamId/stats", get(api::serve::get_stream_stats))
+        .route("/stream/:streamId/logs", get(api::serve::get_stream_logs))
+        .route(
+            "/stream/:streamId/restore",
+            post(api::serve::restore_stream),
+        )
+        .route("/stream/:streamId/token", post(api::playback::issue_token))
+        .route("/stream/:streamId/unlock", post(api::playback::unlock))
+        .route("/stream/:streamId/key/:keyId", get(api::serve::serve_key))
+        .route("/stream/:streamId/tags", put(api::tags::put_stream_tags))
+        .route("/stream/:streamId/clips", get(api::clips::get_clips))
+        .route("/stream/:streamId/clips", post(api::clips::create_clip))
+        .route("/stream/:streamId/download", get(api::recording::download))
         .route(
             "/segment/:streamId/:segmentId",
             get(api::serve::serve_segemnt),
@@ -59,14 +242,87 @@ async fn main() {
         .route("/upload", post(api::upload::upload))
         .route("/upload/ws", get(api::upload::upload_ws))
         .route("/streams", get(api::serve::get_streams))
-        .layer(CorsLayer::new().allow_origin(AllowOrigin::predicate(
-            |_origin: &HeaderValue, _request_parts: &Parts| true,
-        )))
-        .with_state(db_pool)
+        .route("/trash", get(api::serve::get_trash))
+        .route("/tags", get(api::tags::get_tags))
+        .route("/events", get(api::events::events))
+        .route("/webhooks", get(api::webhooks::get_webhooks))
+        .route("/webhooks", post(api::webhooks::create_webhook))
+        .route(
+            "/webhooks/:webhookId",
+            delete(api::webhooks::delete_webhook),
+        )
+        .route(
+            "/webhooks/:webhookId/deliveries",
+            get(api::webhooks::get_deliveries),
+        )
+        .route("/channels", get(api::channels::get_channels))
+        .route("/channels", post(api::channels::create_channel))
+        .route("/channels/:channelId", get(api::channels::get_channel))
+        .route("/channels/:channelId/live", get(api::channels::live))
+        .route(
+            "/channels/:channelId/broadcasts",
+            get(api::channels::get_broadcasts),
+        )
+        .route("/retention/report", get(api::retention::get_report))
+        .route("/metrics", get(metrics::metrics))
+        .layer(axum::middleware::from_fn(metrics::track_http))
+        .layer(cors)
         .with_state(app_state)
-        .layer(TraceLayer::new_for_http());
+        .layer(TraceLayer::new_for_http().make_span_with(logging::request_span));
+
+    let listeners = match args.server.listeners().await {
+        Ok(listeners) => listeners,
+        Err(err) => {
+            error!(%err, "Failed to listen");
+            exit(1);
+        }
+    };
+    if let Some(tls) = &tls {
+        tls.spawn_reloader();
+        if let Some(port) = args.tls.http_redirect_port {
+            let redirect_addr = SocketAddr::new(socket_addr.ip(), port);
+            let redirect_listener = match tokio::net::TcpListener::bind(&redirect_addr).await {
+                Ok(listener) => listener,
+                Err(err) => {
+                    error!(%err, %redirect_addr, "Failed to listen for HTTP redirects");
+                    exit(1);
+                }
+            };
+            // HTTPS on a Unix domain socket is reached through a proxy, which
+            // is assumed to serve it on the default port
+            let https_port = listeners
+                .iter()
+                .find_map(server::Listener::port)
+                .unwrap_or(443);
+            info!(https_port, "redirecting to https from {}", redirect_addr);
+            tokio::spawn(server::serve(
+                server::Listener::Tcp(redirect_listener),
+                None,
+                tls::redirect(https_port),
+                shutdown.clone(),
+            ));
+        }
+    }
+    let server = futures::future::join_all(listeners.into_iter().map(|listener| {
+        info!(tls = tls.is_some(), "listening on {}", listener);
+        server::serve(
+            listener,
+            tls.as_ref().map(tls::Tls::acceptor),
+            app.clone(),
+            shutdown.clone(),
+        )
+    }));
+    let grace_period_elapsed = async {
+        shutdown.cancelled().await;
+        tokio::time::sleep(shutdown::SHUTDOWN_GRACE_PERIOD).await;
+    };
+    tokio::select! {
+        _ = server => (),
+        _ = grace_period_elapsed => warn!("Timed out waiting for requests to finish"),
+    }
 
-    let listener = tokio::net::TcpListener::bind(&socket_addr).await.unwrap();
-    info!("listening on {}", socket_addr);
-    axum::serve(listener, app).await.unwrap();
+    shutdown.drain(shutdown::SHUTDOWN_GRACE_PERIOD).await;
+    shutdown::interrupt_unfinished(&db, &events).await;
+    db.close().await;
+    info!("Shut down");
 }
@@ -0,0 +1,324 @@
+use std::{sync::LazyLock, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hyper::StatusCode;
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge,
+    Opts, Registry, TextEncoder,
+};
+use tracing::{
+    error,
+    field::{Field, Visit},
+    Event, Subscriber,
+};
+use tracing_subscriber::{filter::Targets, layer::Context, registry::LookupSpan, Layer};
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    ffmpeg_processes: IntGauge,
+    ffmpeg_failures: IntCounterVec,
+    transcode_duration: HistogramVec,
+    ingested_bytes: IntCounterVec,
+    pub segment_serve_duration: Histogram,
+    sqlite_query_duration: HistogramVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled per route"),
+            &["method", "route", "status"],
+        )
+        .expect("valid metric");
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time taken to respond to HTTP requests per route",
+            ),
+            &["method", "route"],
+        )
+        .expect("valid metric");
+        let ffmpeg_processes =
+            IntGauge::new("ffmpeg_processes", "Running ffmpeg processes").expect("valid metric");
+        let ffmpeg_failures = IntCounterVec::new(
+            Opts::new("ffmpeg_failures_total", "ffmpeg runs that did not succeed"),
+            &["kind"],
+        )
+        .expect("valid metric");
+        let transcode_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "transcode_duration_seconds",
+                "Wall clock time of successful ffmpeg runs",
+            )
+            .buckets(exponential_buckets(1.0, 2.0, 14).expect("valid buckets")),
+            &["kind"],
+        )
+        .expect("valid metric");
+        let ingested_bytes = IntCounterVec::new(
+            Opts::new("ingested_bytes_total", "Bytes fed into ffmpeg per stream"),
+            &["stream_id"],
+        )
+        .expect("valid metric");
+        let segment_serve_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "segment_serve_duration_seconds",
+                "Time taken to start serving a segment",
+            )
+            .buckets(exponential_buckets(0.0005, 2.0, 14).expect("valid buckets")),
+        )
+        .expect("valid metric");
+        let sqlite_query_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "sqlite_query_duration_seconds",
+                "Time taken to run SQLite queries",
+            )
+            .buckets(exponential_buckets(0.0001, 2.0, 16).expect("valid buckets")),
+            &["query"],
+        )
+        .expect("valid metric");
+
+        registry
+            .register(Box::new(http_requests.clone()))
+            .expect("metric registered once");
+        registry
+            .register(Box::new(http_request_duration.clone()))
+            .expect("metric registered once");
+        registry
+            .register(Box::new(ffmpeg_processes.clone()))
+            .expect("metric registered once");
+        registry
+            .register(Box::new(ffmpeg_failures.clone()))
+            .expect("metric registered once");
+        registry
+            .register(Box::new(transcode_duration.clone()))
+            .expect("metric registered once");
+        registry
+            .register(Box::new(ingested_bytes.clone()))
+            .expect("metric registered once");
+        registry
+            .register(Box::new(segment_serve_duration.clone()))
+            .expect("metric registered once");
+        registry
+            .register(Box::new(sqlite_query_duration.clone()))
+            .expect("metric registered once");
+
+        Self {
+            registry,
+            http_requests,
+            http_request_duration,
+            ffmpeg_processes,
+            ffmpeg_failures,
+            transcode_duration,
+            ingested_bytes,
+            segment_serve_duration,
+            sqlite_query_duration,
+        }
+    }
+
+    pub fn record_ingested(&self, stream_id: &str, bytes: usize) {
+        self.ingested_bytes
+            .with_label_values(&[stream_id])
+            .inc_by(bytes as u64);
+    }
+
+    /// Drops the per stream series so deleted streams do not linger in every scrape.
+    pub fn forget_stream(&self, stream_id: &str) {
+        let _ = self.ingested_bytes.remove_label_values(&[stream_id]);
+    }
+}
+
+/// Tracks one ffmpeg process, counting it as running until dropped. Runs that
+/// are dropped without calling [`FfmpegRun::succeeded`] count as failures.
+pub struct FfmpegRun {
+    kind: &'static str,
+    started: Instant,
+    succeeded: bool,
+}
+
+impl FfmpegRun {
+    /// `kind` is what the process is doing, `upload`, `live`, `clip` or `recording`.
+    pub fn start(kind: &'static str) -> Self {
+        METRICS.ffmpeg_processes.inc();
+        Self {
+            kind,
+            started: Instant::now(),
+            succeeded: false,
+        }
+    }
+
+    pub fn succeeded(mut self) {
+        self.succeeded = true;
+    }
+}
+
+impl Drop for FfmpegRun {
+    fn drop(&mut self) {
+        METRICS.ffmpeg_processes.dec();
+        if self.succeeded {
+            METRICS
+                .transcode_duration
+                .with_label_values(&[self.kind])
+                .observe(self.started.elapsed().as_secs_f64());
+        } else {
+            METRICS
+                .ffmpeg_failures
+                .with_label_values(&[self.kind])
+                .inc();
+        }
+    }
+}
+
+/// Counts and times every request by its route template rather than the raw
+/// path, so stream ids do not explode the label space.
+pub async fn track_http(matched_path: Option<MatchedPath>, req: Request, next: Next) -> Response {
+    let route = matched_path
+        .as_ref()
+        .map(MatchedPath::as_str)
+        .unwrap_or("unmatched")
+        .to_string();
+    let method = req.method().to_string();
+
+    let started = Instant::now();
+    let response = next.run(req).await;
+
+    METRICS
+        .http_request_duration
+        .with_label_values(&[&method, &route])
+        .observe(started.elapsed().as_secs_f64());
+    METRICS
+        .http_requests
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .inc();
+
+    response
+}
+
+pub async fn metrics() -> Response {
+    let mut buffer = Vec::new();
+    let encoder = TextEncoder::new();
+    if let Err(err) = encoder.encode(&METRICS.registry.gather(), &mut buffer) {
+        error!(%err, "Failed to encode metrics");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+
+    (
+        [(header::CONTENT_TYPE, encoder.format_type().to_string())],
+        buffer,
+    )
+        .into_response()
+}
+
+/// Observes the timings sqlx logs for every statement it runs.
+pub fn sqlite_query_layer<S>() -> impl Layer<S>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    SqliteQueryLayer.with_filter(Targets::new().with_target("sqlx::query", tracing::Level::TRACE))
+}
+
+struct SqliteQueryLayer;
+
+impl<S: Subscriber> Layer<S> for SqliteQueryLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut visitor = QueryVisitor::default();
+        event.record(&mut visitor);
+
+        if let (Some(summary), Some(elapsed)) = (visitor.summary, visitor.elapsed_secs) {
+            let query = summary.trim_end_matches(" …");
+            METRICS
+                .sqlite_query_duration
+                .with_label_values(&[query])
+                .observe(elapsed);
+        }
+    }
+}
+
+#[derive(Default)]
+struct QueryVisitor {
+    summary: Option<String>,
+    elapsed_secs: Option<f64>,
+}
+
+impl Visit for QueryVisitor {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        if field.name() == "elapsed_secs" {
+            self.elapsed_secs = Some(value);
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "summary" {
+            self.summary = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "summary" && self.summary.is_none() {
+            self.summary = Some(format!("{:?}", value).trim_matches('"').to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn scrape() -> String {
+        let body = axum::body::to_bytes(metrics().await.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn requests_are_counted_by_route_template() {
+        let app = Router::new()
+            .route("/metrics-test/:id", get(|| async { "ok" }))
+            .layer(axum::middleware::from_fn(track_http));
+        for id in ["a", "b"] {
+            app.clone()
+                .oneshot(
+                    Request::get(format!("/metrics-test/{}", id))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let scraped = scrape().await;
+        assert!(
+            scraped.contains(
+                r#"http_requests_total{method="GET",route="/metrics-test/:id",status="200"} 2"#
+            ),
+            "{}",
+            scraped
+        );
+        assert!(!scraped.contains("/metrics-test/a"), "{}", scraped);
+    }
+
+    #[tokio::test]
+    async fn forgotten_streams_leave_the_scrape() {
+        METRICS.record_ingested("metrics-test-stream", 10);
+        assert!(scrape()
+            .await
+            .contains(r#"ingested_bytes_total{stream_id="metrics-test-stream"} 10"#));
+
+        METRICS.forget_stream("metrics-test-stream");
+        assert!(!scrape().await.contains("metrics-test-stream"));
+    }
+}
@@ -0,0 +1 @@
+alter table `streams` add column `pinned` boolean not null default 0;
@@ -0,0 +1,3 @@
+alter table `streams` add column `deletedAt` datetime;
+
+create index `streams_deleted_at` on `streams` (`deletedAt`);
@@ -0,0 +1 @@
+alter table `streams` add column `passwordHash` text;
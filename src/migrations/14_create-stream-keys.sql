@@ -0,0 +1,9 @@
+-- Not a foreign key, clips get their keys before the clip row is inserted.
+-- Keys are deleted along with their stream by `remove_stream`.
+create table `streamKeys` (
+    `id` varchar(255) not null,
+    `streamId` varchar(255) not null,
+    `key` blob not null,
+    `createdAt` datetime not null,
+    primary key (`streamId`, `id`)
+);
@@ -0,0 +1,20 @@
+create table `channels` (
+    `id` varchar(255) not null primary key,
+    `name` varchar(255) not null,
+    `description` varchar(255) not null,
+    `owner` varchar(255) not null,
+    `defaultWidth` int,
+    `defaultHeight` int,
+    `createdAt` datetime not null
+);
+
+create table `broadcasts` (
+    `id` varchar(255) not null primary key,
+    `channelId` varchar(255) not null references `channels` (`id`) on delete cascade,
+    `streamId` varchar(255) not null unique references `streams` (`id`) on delete cascade,
+    `kind` varchar(16) not null,
+    `startedAt` datetime not null,
+    `endedAt` datetime
+);
+
+create index `broadcasts_channel` on `broadcasts` (`channelId`, `startedAt`);
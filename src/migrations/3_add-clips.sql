@@ -0,0 +1,5 @@
+alter table `streams` add column `parentId` varchar(255) references `streams` (`id`) on delete set null;
+alter table `streams` add column `clipStart` real;
+alter table `streams` add column `clipEnd` real;
+
+create index `streams_parent` on `streams` (`parentId`);
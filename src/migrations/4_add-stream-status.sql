@@ -0,0 +1,2 @@
+alter table `streams` add column `status` varchar(16) not null default 'ended';
+alter table `streams` add column `endTime` datetime;
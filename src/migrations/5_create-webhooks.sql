@@ -0,0 +1,22 @@
+create table `webhooks` (
+    `id` varchar(255) not null primary key,
+    `url` varchar(2048) not null,
+    `secret` varchar(255) not null,
+    `events` varchar(255) not null,
+    `createdAt` datetime not null
+);
+
+create table `webhookDeliveries` (
+    `id` varchar(255) not null primary key,
+    `webhookId` varchar(255) not null references `webhooks` (`id`) on delete cascade,
+    `event` varchar(32) not null,
+    `payload` text not null,
+    `status` varchar(16) not null,
+    `attempts` int not null default 0,
+    `responseStatus` int,
+    `error` text,
+    `createdAt` datetime not null,
+    `updatedAt` datetime not null
+);
+
+create index `webhookDeliveries_webhook` on `webhookDeliveries` (`webhookId`, `createdAt`);
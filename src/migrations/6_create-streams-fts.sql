@@ -0,0 +1,23 @@
+create virtual table `streams_fts` using fts5 (
+    `name`,
+    `description`,
+    content = 'streams',
+    content_rowid = 'rowid'
+);
+
+create trigger `streams_fts_insert` after insert on `streams` begin
+    insert into `streams_fts` (rowid, `name`, `description`) values (new.rowid, new.`name`, new.`description`);
+end;
+
+create trigger `streams_fts_delete` after delete on `streams` begin
+    insert into `streams_fts` (`streams_fts`, rowid, `name`, `description`) values ('delete', old.rowid, old.`name`, old.`description`);
+end;
+
+create trigger `streams_fts_update` after update of `name`, `description` on `streams` begin
+    insert into `streams_fts` (`streams_fts`, rowid, `name`, `description`) values ('delete', old.rowid, old.`name`, old.`description`);
+    insert into `streams_fts` (rowid, `name`, `description`) values (new.rowid, new.`name`, new.`description`);
+end;
+
+insert into `streams_fts` (`streams_fts`) values ('rebuild');
+
+create index `streams_start_time` on `streams` (`startTime`, `id`);
@@ -0,0 +1,12 @@
+create table `tags` (
+    `id` integer not null primary key autoincrement,
+    `name` varchar(64) not null unique
+);
+
+create table `streamTags` (
+    `streamId` varchar(255) not null references `streams` (`id`) on delete cascade,
+    `tagId` integer not null references `tags` (`id`) on delete cascade,
+    primary key (`streamId`, `tagId`)
+);
+
+create index `streamTags_tag` on `streamTags` (`tagId`);
@@ -0,0 +1,2 @@
+alter table `streams` add column `peakViewers` int not null default 0;
+alter table `streams` add column `totalViews` int not null default 0;
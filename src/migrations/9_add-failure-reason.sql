@@ -0,0 +1 @@
+alter table `streams` add column `failureReason` text;
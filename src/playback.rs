@@ -0,0 +1,356 @@
+//! Signed, expiring playback tokens. A token grants access to one stream's
+//! playlist and segments until it expires, optionally only from one address.
+//! Tokens look like `{expires}_{ip}_{signature}`, where `ip` may be empty.
+//! Unlocking a password protected stream hands one out as a cookie.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRef, FromRequestParts, Query},
+    http::{request::Parts, HeaderMap},
+};
+use clap::Args;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use tracing::warn;
+
+use crate::auth::{AdminToken, IsAdmin};
+use crate::utils::get_cookie;
+
+/// How long unlocking a password protected stream lasts.
+pub const UNLOCK_TTL: Duration = Duration::from_secs(30 * 60);
+/// The longest a playback token can be issued for.
+pub const MAX_TOKEN_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[derive(Args, Debug)]
+pub struct PlaybackArgs {
+    /// Key playback tokens are signed with. Without one a random key is used and tokens do not survive restarts
+    #[arg(long, env = "PLAYBACK_SECRET", hide_env_values = true)]
+    pub playback_secret: Option<String>,
+
+    /// Require a playback token to watch any stream
+    #[arg(long, env = "REQUIRE_PLAYBACK_TOKEN")]
+    pub require_playback_token: bool,
+
+    /// Seconds a playback token is valid for when the request does not say
+    #[arg(long, env = "PLAYBACK_TOKEN_TTL", default_value_t = 3600, value_parser = clap::value_parser!(u64).range(..=MAX_TOKEN_TTL.as_secs()))]
+    pub playback_token_ttl: u64,
+
+    /// Take the client address from `X-Forwarded-For`, only safe behind a proxy that sets it
+    #[arg(long, env = "TRUST_FORWARDED_FOR")]
+    pub trust_forwarded_for: bool,
+
+    /// How many proxies in front of the server append to `X-Forwarded-For`, the client address is the entry this far from the right
+    #[arg(long, env = "FORWARDED_FOR_HOPS", default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    pub forwarded_for_hops: u64,
+}
+
+#[derive(Clone)]
+pub struct PlaybackSigner {
+    secret: Arc<[u8]>,
+    /// Whether every stream needs a token, not just the ones that ask for it
+    pub required: bool,
+    pub default_ttl: Duration,
+    /// How far from the right of `X-Forwarded-For` the client address is,
+    /// `None` when the header isn't trusted
+    forwarded_for_hops: Option<usize>,
+}
+
+impl std::fmt::Debug for PlaybackSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PlaybackSigner")
+            .field("secret", &"<redacted>")
+            .field("required", &self.required)
+            .field("default_ttl", &self.default_ttl)
+            .field("forwarded_for_hops", &self.forwarded_for_hops)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenError {
+    Malformed,
+    Expired,
+    /// The token is bound to another address
+    WrongAddress,
+    BadSignature,
+}
+
+impl PlaybackSigner {
+    pub fn from_args(args: &PlaybackArgs) -> PlaybackSigner {
+        let secret: Arc<[u8]> = match &args.playback_secret {
+            Some(secret) => secret.as_bytes().into(),
+            None => {
+                warn!("No playback secret configured, tokens will not survive a restart");
+                let mut secret = uuid::Uuid::new_v4().as_bytes().to_vec();
+                secret.extend_from_slice(uuid::Uuid::new_v4().as_bytes());
+                secret.into()
+            }
+        };
+        PlaybackSigner {
+            secret,
+            required: args.require_playback_token,
+            default_ttl: Duration::from_secs(args.playback_token_ttl),
+            forwarded_for_hops: args
+                .trust_forwarded_for
+                .then_some(args.forwarded_for_hops as usize),
+        }
+    }
+
+    pub fn issue(
+        &self,
+        stream_id: &str,
+        expires_at: chrono::DateTime<chrono::Utc>,
+        ip: Option<IpAddr>,
+    ) -> String {
+        let expires = expires_at.timestamp();
+        let ip = ip.map(|ip| ip.to_string()).unwrap_or_default();
+        let signature = hex::encode(self.mac(stream_id, expires, &ip).finalize().into_bytes());
+        format!("{}_{}_{}", expires, ip, signature)
+    }
+
+    pub fn verify(
+        &self,
+        stream_id: &str,
+        token: &str,
+        client_ip: Option<IpAddr>,
+    ) -> Result<(), TokenError> {
+        let mut parts = token.splitn(3, '_');
+        let (Some(expires), Some(ip), Some(signature)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(TokenError::Malformed);
+        };
+        let expires: i64 = expires.parse().map_err(|_| TokenError::Malformed)?;
+        let signature = hex::decode(signature).map_err(|_| TokenError::Malformed)?;
+
+        self.mac(stream_id, expires, ip)
+            .verify_slice(&signature)
+            .map_err(|_| TokenError::BadSignature)?;
+        if expires < chrono::Utc::now().timestamp() {
+            return Err(TokenError::Expired);
+        }
+        if !ip.is_empty() && client_ip.map(|client_ip| client_ip.to_string()).as_deref() != Some(ip)
+        {
+            return Err(TokenError::WrongAddress);
+        }
+        Ok(())
+    }
+
+    fn mac(&self, stream_id: &str, expires: i64, ip: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC takes keys of any size");
+        mac.update(format!("{}\n{}\n{}", stream_id, expires, ip).as_bytes());
+        mac
+    }
+}
+
+/// The address a request came from, for binding tokens to it. `None` when it
+/// is unknown.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientIp
+where
+    S: Send + Sync,
+    PlaybackSigner: FromRef<S>,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(hops) = PlaybackSigner::from_ref(state).forwarded_for_hops {
+            let forwarded = forwarded_for(&parts.headers, hops);
+            if forwarded.is_some() {
+                return Ok(ClientIp(forwarded));
+            }
+        }
+
+        Ok(ClientIp(
+            parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip()),
+        ))
+    }
+}
+
+/// The entry `hops` from the right of `X-Forwarded-For`. Every proxy appends
+/// the address it got the request from, anything further left came from the
+/// client and can't be trusted.
+fn forwarded_for(headers: &HeaderMap, hops: usize) -> Option<IpAddr> {
+    let entries: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    let entry = entries.get(entries.len().checked_sub(hops)?)?;
+    entry.trim().parse().ok()
+}
+
+pub fn unlock_cookie_name(stream_id: &str) -> String {
+    format!("unlock_{}", stream_id)
+}
+
+/// Everything a playback request can prove it may watch a stream with.
+#[derive(Debug)]
+pub struct PlaybackAuth {
+    pub admin: bool,
+    pub client_ip: Option<IpAddr>,
+    /// The `token` query parameter
+    pub token: Option<String>,
+    headers: HeaderMap,
+}
+
+impl PlaybackAuth {
+    pub fn unlock_cookie(&self, stream_id: &str) -> Option<String> {
+        get_cookie(&self.headers, &unlock_cookie_name(stream_id))
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for PlaybackAuth
+where
+    S: Send + Sync,
+    AdminToken: FromRef<S>,
+    PlaybackSigner: FromRef<S>,
+{
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Ok(IsAdmin(admin)) = IsAdmin::from_request_parts(parts, state).await;
+        let Ok(ClientIp(client_ip)) = ClientIp::from_request_parts(parts, state).await;
+        let token = Query::<TokenQuery>::try_from_uri(&parts.uri)
+            .ok()
+            .and_then(|Query(query)| query.token);
+
+        Ok(PlaybackAuth {
+            admin,
+            client_ip,
+            token,
+            headers: parts.headers.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer_args() -> PlaybackArgs {
+        PlaybackArgs {
+            playback_secret: Some("secret".to_string()),
+            require_playback_token: false,
+            playback_token_ttl: 3600,
+            trust_forwarded_for: false,
+            forwarded_for_hops: 1,
+        }
+    }
+
+    fn signer() -> PlaybackSigner {
+        PlaybackSigner::from_args(&signer_args())
+    }
+
+    fn in_an_hour() -> chrono::DateTime<chrono::Utc> {
+        chrono::Utc::now() + chrono::Duration::hours(1)
+    }
+
+    #[test]
+    fn verify_accepts_issued_tokens() {
+        let signer = signer();
+        let token = signer.issue("abc", in_an_hour(), None);
+        assert_eq!(signer.verify("abc", &token, None), Ok(()));
+        assert_eq!(
+            signer.verify("abc", &token, Some("10.0.0.1".parse().unwrap())),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn verify_rejects_expired_tokens() {
+        let signer = signer();
+        let token = signer.issue(
+            "abc",
+            chrono::Utc::now() - chrono::Duration::seconds(1),
+            None,
+        );
+        assert_eq!(signer.verify("abc", &token, None), Err(TokenError::Expired));
+    }
+
+    #[test]
+    fn verify_rejects_other_addresses() {
+        let signer = signer();
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let token = signer.issue("abc", in_an_hour(), Some(ip));
+        assert_eq!(signer.verify("abc", &token, Some(ip)), Ok(()));
+        assert_eq!(
+            signer.verify("abc", &token, Some("10.0.0.2".parse().unwrap())),
+            Err(TokenError::WrongAddress)
+        );
+        assert_eq!(
+            signer.verify("abc", &token, None),
+            Err(TokenError::WrongAddress)
+        );
+    }
+
+    #[test]
+    fn verify_rejects_tampered_tokens() {
+        let signer = signer();
+        let token = signer.issue("abc", in_an_hour(), Some("10.0.0.1".parse().unwrap()));
+        let (expires, rest) = token.split_once('_').unwrap();
+        let (_, signature) = rest.split_once('_').unwrap();
+
+        let later = format!("{}_{}", expires.parse::<i64>().unwrap() + 1, rest);
+        assert_eq!(
+            signer.verify("abc", &later, None),
+            Err(TokenError::BadSignature)
+        );
+        let unbound = format!("{}__{}", expires, signature);
+        assert_eq!(
+            signer.verify("abc", &unbound, None),
+            Err(TokenError::BadSignature)
+        );
+        assert_eq!(
+            signer.verify("other", &token, None),
+            Err(TokenError::BadSignature)
+        );
+        assert_eq!(
+            signer.verify("abc", "123_", None),
+            Err(TokenError::Malformed)
+        );
+    }
+
+    #[test]
+    fn verify_rejects_tokens_of_another_secret() {
+        let token = PlaybackSigner::from_args(&PlaybackArgs {
+            playback_secret: Some("other".to_string()),
+            ..signer_args()
+        })
+        .issue("abc", in_an_hour(), None);
+        assert_eq!(
+            signer().verify("abc", &token, None),
+            Err(TokenError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn forwarded_for_counts_hops_from_the_right() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "1.1.1.1, 2.2.2.2".parse().unwrap());
+        headers.append("x-forwarded-for", "3.3.3.3".parse().unwrap());
+        assert_eq!(forwarded_for(&headers, 1), Some("3.3.3.3".parse().unwrap()));
+        assert_eq!(forwarded_for(&headers, 2), Some("2.2.2.2".parse().unwrap()));
+        assert_eq!(forwarded_for(&headers, 3), Some("1.1.1.1".parse().unwrap()));
+        assert_eq!(forwarded_for(&headers, 4), None);
+        assert_eq!(forwarded_for(&HeaderMap::new(), 1), None);
+    }
+}
@@ -0,0 +1,421 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use clap::ValueEnum;
+use sqlx::{Pool, Sqlite};
+use tracing::{error, info, instrument, warn};
+
+use crate::api::data::StreamStatus;
+use crate::api::serve::remove_stream;
+use crate::api::upload::finish_stream;
+use crate::events::EventBus;
+use crate::ffmpeg_log;
+use crate::hls::{segment_file_name, Playlist};
+use crate::storage::{self, object_key, SharedStorage, Storage, PLAYLIST_CONTENT_TYPE};
+use crate::utils::ResourceDir;
+
+/// What to do with what a crash left behind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OrphanPolicy {
+    /// Only log what is wrong
+    Report,
+    /// Finalize incomplete playlists and streams, keeping orphans around
+    Repair,
+    /// Repair, then delete rows without a directory and directories without a row
+    Clean,
+}
+
+#[derive(Debug, Default)]
+pub struct ReconcileReport {
+    /// Rows whose directory is gone and that remote storage doesn't have either
+    pub missing_dirs: usize,
+    /// Directories without a row
+    pub orphaned_dirs: usize,
+    /// Rows still processing or live from before the restart
+    pub unfinished_streams: usize,
+    /// Playlists that were missing `EXT-X-ENDLIST` or listed missing segments
+    pub repaired_playlists: usize,
+}
+
+/// Compares the streams table with the resource directory after a restart.
+/// Nothing is ingesting yet, so anything still processing or live was cut off.
+#[instrument(skip(db, resource_dir, storage, events))]
+pub async fn reconcile(
+    db: &Pool<Sqlite>,
+    resource_dir: &ResourceDir,
+    storage: &SharedStorage,
+    events: &EventBus,
+    policy: OrphanPolicy,
+) -> Result<ReconcileReport, anyhow::Error> {
+    let mut report = ReconcileReport::default();
+    let repair = policy != OrphanPolicy::Report;
+
+    let rows: Vec<(String, StreamStatus)> =
+        sqlx::query_as(r#"SELECT `id`, `status` FROM `streams`"#)
+            .fetch_all(db)
+            .await?;
+    let mut dirs = stream_dirs(resource_dir).await?;
+
+    for (stream_id, status) in rows {
+        let unfinished = matches!(status, StreamStatus::Processing | StreamStatus::Live);
+        if unfinished {
+            report.unfinished_streams += 1;
+        }
+
+        if !dirs.remove(&stream_id) {
+            // Remote storage serves streams whose local copy is gone
+            match stored_playlist(storage.as_ref(), &stream_id).await {
+                Ok(Some(playlist)) => {
+                    if unfinished && repair {
+                        finish_stored(
+                            db,
+                            storage.as_ref(),
+                            events,
+                            &stream_id,
+                            playlist,
+                            &mut report,
+                        )
+                        .await;
+                    } else if unfinished {
+                        warn!(stream_id, ?status, "Stream was cut off");
+                    }
+                    continue;
+                }
+                Ok(None) => (),
+                Err(err) => {
+                    error!(%err, stream_id, "Failed to look for stream in storage");
+                    continue;
+                }
+            }
+
+            report.missing_dirs += 1;
+            warn!(stream_id, ?status, "Stream has no directory");
+            match policy {
+                OrphanPolicy::Report => (),
+                OrphanPolicy::Repair if status != StreamStatus::Failed => {
+                    ffmpeg_log::save_failure_reason(db, &stream_id, "Stream directory is missing")
+                        .await;
+                    finish_stream(db, events, &stream_id, StreamStatus::Failed).await;
+                }
+                OrphanPolicy::Repair => (),
+                OrphanPolicy::Clean => {
+                    remove_stream(db, resource_dir, storage, events, &stream_id).await?;
+                }
+            }
+            continue;
+        }
+
+        if !repair {
+            if unfinished {
+                warn!(stream_id, ?status, "Stream was cut off");
+            }
+            continue;
+        }
+
+        let stream_dir = resource_dir.stream_dir(&stream_id);
+        let has_segments = match finalize_playlist(&stream_dir).await {
+            Ok(Finalized::Untouched) => true,
+            Ok(Finalized::Repaired) => {
+                report.repaired_playlists += 1;
+                info!(stream_id, "Finalized playlist");
+                if let Err(err) = storage::upload_stream(storage, resource_dir, &stream_id).await {
+                    error!(%err, stream_id, "Failed to upload finalized playlist");
+                }
+                true
+            }
+            Ok(Finalized::Empty) => false,
+            Err(err) => {
+                error!(%err, stream_id, "Failed to finalize playlist");
+                continue;
+            }
+        };
+
+        if unfinished {
+            if has_segments {
+                warn!(stream_id, "Marking cut off stream as interrupted");
+                finish_stream(db, events, &stream_id, StreamStatus::Interrupted).await;
+            } else {
+                warn!(
+                    stream_id,
+                    "Marking cut off stream without segments as failed"
+                );
+                ffmpeg_log::save_failure_reason(
+                    db,
+                    &stream_id,
+                    "The server stopped before any segment was written",
+                )
+                .await;
+                finish_stream(db, events, &stream_id, StreamStatus::Failed).await;
+            }
+        }
+    }
+
+    for stream_id in dirs {
+        report.orphaned_dirs += 1;
+        warn!(stream_id, "Directory has no stream");
+        if policy == OrphanPolicy::Clean {
+            let path = resource_dir.stream_dir(&stream_id);
+            if let Err(err) = tokio::fs::remove_dir_all(&path).await {
+                error!(%err, ?path, "Failed to remove orphaned directory");
+            }
+            ffmpeg_log::remove(&resource_dir.log_path(&stream_id)).await;
+        }
+    }
+
+    info!(?policy, ?report, "Reconciled streams");
+    Ok(report)
+}
+
+/// The playlist of a stream in remote storage, `None` with local storage since
+/// there the directory is the stream.
+async fn stored_playlist(
+    storage: &dyn Storage,
+    stream_id: &str,
+) -> Result<Option<Playlist>, anyhow::Error> {
+    if storage.is_local() {
+        return Ok(None);
+    }
+    let Some(key) = object_key(stream_id, "index.m3u8") else {
+        return Ok(None);
+    };
+    Ok(storage
+        .read(&key)
+        .await?
+        .map(|body| Playlist::parse(&String::from_utf8_lossy(&body))))
+}
+
+/// Ends a cut off stream that only remote storage has. Its segments can't be
+/// checked without downloading them, so the playlist is trusted.
+async fn finish_stored(
+    db: &Pool<Sqlite>,
+    storage: &dyn Storage,
+    events: &EventBus,
+    stream_id: &str,
+    mut playlist: Playlist,
+    report: &mut ReconcileReport,
+) {
+    if playlist.segments.is_empty() {
+        warn!(
+            stream_id,
+            "Marking cut off stream without segments as failed"
+        );
+        ffmpeg_log::save_failure_reason(
+            db,
+            stream_id,
+            "The server stopped before any segment was written",
+        )
+        .await;
+        finish_stream(db, events, stream_id, StreamStatus::Failed).await;
+        return;
+    }
+
+    if !playlist.ended {
+        playlist.ended = true;
+        let key = format!("{}/index.m3u8", stream_id);
+        match storage
+            .put(&key, playlist.render().into_bytes(), PLAYLIST_CONTENT_TYPE)
+            .await
+        {
+            Ok(()) => {
+                report.repaired_playlists += 1;
+                info!(stream_id, "Finalized playlist");
+            }
+            Err(err) => error!(%err, stream_id, "Failed to finalize stored playlist"),
+        }
+    }
+    warn!(stream_id, "Marking cut off stream as interrupted");
+    finish_stream(db, events, stream_id, StreamStatus::Interrupted).await;
+}
+
+async fn stream_dirs(resource_dir: &ResourceDir) -> Result<HashSet<String>, anyhow::Error> {
+    let mut dirs = HashSet::new();
+    let mut entries = tokio::fs::read_dir(resource_dir.root()).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if !entry.file_type().await?.is_dir() {
+            continue;
+        }
+        if let Some(name) = entry.file_name().to_str() {
+            dirs.insert(name.to_string());
+        }
+    }
+    Ok(dirs)
+}
+
+enum Finalized {
+    Untouched,
+    Repaired,
+    /// There is no playlist or none of its segments made it to disk
+    Empty,
+}
+
+/// Ends the playlist and drops segments that never made it to disk, so players
+/// stop polling it for more.
+async fn finalize_playlist(stream_dir: &Path) -> Result<Finalized, anyhow::Error> {
+    let path = stream_dir.join("index.m3u8");
+    let text = match tokio::fs::read_to_string(&path).await {
+        Ok(text) => text,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Finalized::Empty),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut playlist = Playlist::parse(&text);
+    let listed = playlist.segments.len();
+    let mut segments = Vec::with_capacity(listed);
+    for segment in playlist.segments {
+        let segment_path = stream_dir.join(segment_file_name(&segment.uri));
+        match tokio::fs::metadata(&segment_path).await {
+            Ok(metadata) if metadata.len() > 0 => segments.push(segment),
+            _ => warn!(?segment_path, "Dropping missing segment"),
+        }
+    }
+    playlist.segments = segments;
+
+    if playlist.segments.is_empty() {
+        return Ok(Finalized::Empty);
+    }
+    if playlist.ended && playlist.segments.len() == listed {
+        return Ok(Finalized::Untouched);
+    }
+
+    playlist.ended = true;
+    let tmp_path = stream_dir.join("index.m3u8.part");
+    tokio::fs::write(&tmp_path, playlist.render()).await?;
+    tokio::fs::rename(&tmp_path, &path).await?;
+    Ok(Finalized::Repaired)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use crate::utils::test_db;
+
+    async fn insert_stream(db: &Pool<Sqlite>, id: &str, status: &str) {
+        sqlx::query(
+            r#"insert into streams (id, name, description, startTime, width, height, status) values ($1, '', '', $2, 1920, 1080, $3)"#,
+        )
+        .bind(id)
+        .bind(chrono::Utc::now())
+        .bind(status)
+        .execute(db)
+        .await
+        .unwrap();
+    }
+
+    async fn status(db: &Pool<Sqlite>, id: &str) -> Option<String> {
+        sqlx::query_scalar(r#"SELECT `status` FROM `streams` WHERE `id` = $1"#)
+            .bind(id)
+            .fetch_optional(db)
+            .await
+            .unwrap()
+    }
+
+    fn resource_dir() -> ResourceDir {
+        let root = std::env::temp_dir().join(format!("reconcile-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir(&root).unwrap();
+        ResourceDir::new(root.clone(), root.join("logs"))
+    }
+
+    const PLAYLIST: &str = "#EXTM3U\n#EXT-X-TARGETDURATION:4\n#EXTINF:4.0,\n000.ts\n";
+
+    #[tokio::test]
+    async fn streams_only_in_storage_are_not_missing() {
+        let db = test_db().await;
+        let resource_dir = resource_dir();
+        let bucket = Arc::new(MemoryStorage::default());
+        bucket
+            .put("ended/index.m3u8", PLAYLIST.into(), "")
+            .await
+            .unwrap();
+        bucket
+            .put("live/index.m3u8", PLAYLIST.into(), "")
+            .await
+            .unwrap();
+        insert_stream(&db, "ended", "ended").await;
+        insert_stream(&db, "live", "live").await;
+        insert_stream(&db, "gone", "ended").await;
+        let storage: SharedStorage = bucket.clone();
+
+        let report = reconcile(
+            &db,
+            &resource_dir,
+            &storage,
+            &EventBus::new(16),
+            OrphanPolicy::Clean,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.missing_dirs, 1);
+        assert_eq!(report.repaired_playlists, 1);
+        assert_eq!(status(&db, "ended").await.as_deref(), Some("ended"));
+        assert_eq!(status(&db, "live").await.as_deref(), Some("interrupted"));
+        assert_eq!(status(&db, "gone").await, None);
+        assert!(bucket.read("ended/index.m3u8").await.unwrap().is_some());
+        let live = bucket.read("live/index.m3u8").await.unwrap().unwrap();
+        assert!(Playlist::parse(&String::from_utf8(live).unwrap()).ended);
+
+        std::fs::remove_dir_all(resource_dir.root()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn repair_finishes_what_a_crash_left_behind() {
+        let db = test_db().await;
+        let resource_dir = resource_dir();
+        let storage = storage::from_args(
+            &storage::StorageArgs {
+                storage: storage::StorageKind::Local,
+                s3_endpoint: None,
+                s3_bucket: None,
+                s3_region: String::new(),
+                s3_access_key_id: None,
+                s3_secret_access_key: None,
+                s3_serve: crate::s3::S3Serve::Proxy,
+                s3_presign_expiry: 0,
+            },
+            &resource_dir,
+        )
+        .unwrap();
+        // Cut off after the first segment, while the second was being written
+        let cut = resource_dir.stream_dir("cut");
+        std::fs::create_dir(&cut).unwrap();
+        std::fs::write(
+            cut.join("index.m3u8"),
+            format!("{}#EXTINF:4.0,\n001.ts\n", PLAYLIST),
+        )
+        .unwrap();
+        std::fs::write(cut.join("000.ts"), b"ts").unwrap();
+        std::fs::write(cut.join("001.ts"), b"").unwrap();
+        insert_stream(&db, "cut", "live").await;
+        std::fs::create_dir(resource_dir.stream_dir("empty")).unwrap();
+        insert_stream(&db, "empty", "processing").await;
+        insert_stream(&db, "gone", "ended").await;
+        std::fs::create_dir(resource_dir.stream_dir("orphan")).unwrap();
+
+        let report = reconcile(
+            &db,
+            &resource_dir,
+            &storage,
+            &EventBus::new(16),
+            OrphanPolicy::Repair,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.missing_dirs, 1);
+        assert_eq!(report.orphaned_dirs, 1);
+        assert_eq!(report.unfinished_streams, 2);
+        assert_eq!(report.repaired_playlists, 1);
+        assert_eq!(status(&db, "cut").await.as_deref(), Some("interrupted"));
+        assert_eq!(status(&db, "empty").await.as_deref(), Some("failed"));
+        assert_eq!(status(&db, "gone").await.as_deref(), Some("failed"));
+        let playlist = Playlist::parse(&std::fs::read_to_string(cut.join("index.m3u8")).unwrap());
+        assert!(playlist.ended);
+        assert_eq!(playlist.segments.len(), 1);
+        assert!(resource_dir.stream_dir("orphan").exists());
+
+        std::fs::remove_dir_all(resource_dir.root()).unwrap();
+    }
+}
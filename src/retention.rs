@@ -0,0 +1,288 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::Args;
+use serde::Serialize;
+use sqlx::{Pool, Sqlite};
+use tracing::{error, info, instrument, warn};
+
+use crate::api::data::StreamStatus;
+use crate::api::serve::remove_stream;
+use crate::events::EventBus;
+use crate::shutdown::Shutdown;
+use crate::storage::SharedStorage;
+use crate::utils::{parse_size, ResourceDir};
+
+/// Limits on how much is kept, unset limits are not enforced. Pinned streams and
+/// streams still being ingested are never deleted, streams in the trash always
+/// are once their grace period is over.
+#[derive(Args, Debug, Clone)]
+pub struct RetentionPolicy {
+    /// Delete streams that started more than this many days ago
+    #[arg(long = "retention-max-age-days", env = "RETENTION_MAX_AGE_DAYS")]
+    pub max_age_days: Option<u64>,
+
+    /// Delete the oldest streams while the resource dir uses more than this, e.g. `500M` or `20G`
+    #[arg(long = "retention-max-disk", env = "RETENTION_MAX_DISK", value_parser = parse_size)]
+    pub max_disk_bytes: Option<u64>,
+
+    /// Keep only this many of the newest streams of each channel
+    #[arg(long = "retention-max-per-channel", env = "RETENTION_MAX_PER_CHANNEL")]
+    pub max_per_channel: Option<usize>,
+
+    /// Days deleted streams stay in the trash before they are purged
+    #[arg(
+        long = "trash-grace-days",
+        env = "TRASH_GRACE_DAYS",
+        default_value_t = 7
+    )]
+    pub trash_grace_days: u64,
+
+    /// Seconds between retention runs
+    #[arg(
+        long = "retention-interval",
+        env = "RETENTION_INTERVAL",
+        default_value_t = 3600
+    )]
+    pub interval_secs: u64,
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExpiryReason {
+    /// In the trash for longer than the grace period
+    Trashed,
+    MaxAge,
+    ChannelLimit,
+    DiskUsage,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ExpiredStream {
+    pub stream_id: String,
+    pub name: String,
+    pub start_time: chrono::DateTime<chrono::Utc>,
+    pub reason: ExpiryReason,
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RetentionReport {
+    /// Bytes used by stream directories and logs before anything is deleted
+    pub disk_usage: u64,
+    pub expired: Vec<ExpiredStream>,
+}
+
+/// Works out which streams the policy would delete without deleting anything.
+#[instrument(skip(db, resource_dir))]
+pub async fn plan(
+    db: &Pool<Sqlite>,
+    resource_dir: &ResourceDir,
+    policy: &RetentionPolicy,
+) -> Result<RetentionReport, anyhow::Error> {
+    // Newest first, so the per channel limit keeps the start of each channel's list
+    let candidates: Vec<(String, String, chrono::DateTime<chrono::Utc>, Option<String>)> =
+        sqlx::query_as(
+            r#"SELECT s.`id`, s.`name`, s.`startTime`, b.`channelId` FROM `streams` s LEFT JOIN `broadcasts` b ON b.`streamId` = s.`id` WHERE s.`pinned` = 0 AND s.`deletedAt` IS NULL AND s.`status` NOT IN ($1, $2) ORDER BY s.`startTime` DESC, s.`id` DESC"#,
+        )
+        .bind(StreamStatus::Processing)
+        .bind(StreamStatus::Live)
+        .fetch_all(db)
+        .await?;
+    // Longest in the trash first
+    let trashed: Vec<(String, String, chrono::DateTime<chrono::Utc>, chrono::DateTime<chrono::Utc>)> =
+        sqlx::query_as(
+            r#"SELECT `id`, `name`, `startTime`, `deletedAt` FROM `streams` WHERE `deletedAt` IS NOT NULL ORDER BY `deletedAt` ASC"#,
+        )
+        .fetch_all(db)
+        .await?;
+
+    let mut expired = Vec::new();
+    let mut expired_ids = HashSet::new();
+    let mut expire = |stream_id: &str, name: &str, start_time, reason| {
+        if expired_ids.insert(stream_id.to_string()) {
+            expired.push((stream_id.to_string(), name.to_string(), start_time, reason));
+        }
+    };
+
+    let purge_cutoff = chrono::Utc::now() - chrono::Duration::days(policy.trash_grace_days as i64);
+    for (stream_id, name, start_time, deleted_at) in &trashed {
+        if *deleted_at < purge_cutoff {
+            expire(stream_id, name, *start_time, ExpiryReason::Trashed);
+        }
+    }
+
+    if let Some(max_age_days) = policy.max_age_days {
+        let cutoff = chrono::Utc::now() - chrono::Duration::days(max_age_days as i64);
+        for (stream_id, name, start_time, _) in &candidates {
+            if *start_time < cutoff {
+                expire(stream_id, name, *start_time, ExpiryReason::MaxAge);
+            }
+        }
+    }
+
+    if let Some(max_per_channel) = policy.max_per_channel {
+        let mut kept: HashMap<&str, usize> = HashMap::new();
+        for (stream_id, name, start_time, channel_id) in &candidates {
+            let Some(channel_id) = channel_id else {
+                continue;
+            };
+            let count = kept.entry(channel_id).or_default();
+            if *count < max_per_channel {
+                *count += 1;
+            } else {
+                expire(stream_id, name, *start_time, ExpiryReason::ChannelLimit);
+            }
+        }
+    }
+
+    let disk_usage = media_size(resource_dir).await?;
+    let stream_size = |stream_id: &str| {
+        let stream_dir = resource_dir.stream_dir(stream_id);
+        let log_path = resource_dir.log_path(stream_id);
+        async move { dir_size(stream_dir).await + dir_size(log_path).await }
+    };
+
+    let mut report = Vec::with_capacity(expired.len());
+    let mut remaining = disk_usage;
+    for (stream_id, name, start_time, reason) in expired {
+        let bytes = stream_size(&stream_id).await;
+        remaining = remaining.saturating_sub(bytes);
+        report.push(ExpiredStream {
+            stream_id,
+            name,
+            start_time,
+            reason,
+            bytes,
+        });
+    }
+
+    if let Some(max_disk_bytes) = policy.max_disk_bytes {
+        // Empty the trash before touching anything that is still listed
+        let oldest_first = trashed
+            .iter()
+            .map(|(stream_id, name, start_time, _)| (stream_id, name, start_time))
+            .chain(
+                candidates
+                    .iter()
+                    .rev()
+                    .map(|(stream_id, name, start_time, _)| (stream_id, name, start_time)),
+            );
+        for (stream_id, name, start_time) in oldest_first {
+            if remaining <= max_disk_bytes {
+                break;
+            }
+            if expired_ids.contains(stream_id) {
+                continue;
+            }
+            let bytes = stream_size(stream_id).await;
+            remaining = remaining.saturating_sub(bytes);
+            report.push(ExpiredStream {
+                stream_id: stream_id.clone(),
+                name: name.clone(),
+                start_time: *start_time,
+                reason: ExpiryReason::DiskUsage,
+                bytes,
+            });
+        }
+        if remaining > max_disk_bytes {
+            warn!(
+                remaining,
+                max_disk_bytes, "Disk usage stays over the limit after deleting every candidate"
+            );
+        }
+    }
+
+    Ok(RetentionReport {
+        disk_usage,
+        expired: report,
+    })
+}
+
+/// Periodically purges the trash and deletes the streams the policy expires.
+pub fn spawn_enforcer(
+    db: Pool<Sqlite>,
+    resource_dir: ResourceDir,
+    storage: SharedStorage,
+    events: EventBus,
+    policy: RetentionPolicy,
+    shutdown: Shutdown,
+) {
+    info!(?policy, "Enforcing retention");
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(policy.interval_secs));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => (),
+                _ = shutdown.cancelled() => break,
+            }
+            if let Err(err) = enforce(&db, &resource_dir, &storage, &events, &policy).await {
+                error!(%err, "Failed to enforce retention");
+            }
+        }
+    });
+}
+
+#[instrument(skip_all)]
+async fn enforce(
+    db: &Pool<Sqlite>,
+    resource_dir: &ResourceDir,
+    storage: &SharedStorage,
+    events: &EventBus,
+    policy: &RetentionPolicy,
+) -> Result<(), anyhow::Error> {
+    let report = plan(db, resource_dir, policy).await?;
+    for expired in &report.expired {
+        info!(
+            stream_id = expired.stream_id,
+            reason = ?expired.reason,
+            bytes = expired.bytes,
+            "Expiring stream"
+        );
+        // One stream that can't be removed shouldn't keep the rest around
+        if let Err(err) = remove_stream(db, resource_dir, storage, events, &expired.stream_id).await
+        {
+            error!(%err, stream_id = expired.stream_id, "Failed to expire stream");
+        }
+    }
+    Ok(())
+}
+
+/// What counts against the disk limit, every directory in the resource dir but
+/// not the database next to them, and the ffmpeg logs.
+async fn media_size(resource_dir: &ResourceDir) -> Result<u64, anyhow::Error> {
+    let mut total = dir_size(resource_dir.logs_dir().to_path_buf()).await;
+    let mut entries = tokio::fs::read_dir(resource_dir.root()).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_dir() {
+            total += dir_size(entry.path()).await;
+        }
+    }
+    Ok(total)
+}
+
+/// Total size of the files under `path`, which may also be a single file.
+async fn dir_size(path: PathBuf) -> u64 {
+    let mut total = 0;
+    let mut pending = vec![path];
+    while let Some(path) = pending.pop() {
+        let Ok(metadata) = tokio::fs::symlink_metadata(&path).await else {
+            continue;
+        };
+        if !metadata.is_dir() {
+            total += metadata.len();
+            continue;
+        }
+        let Ok(mut entries) = tokio::fs::read_dir(&path).await else {
+            continue;
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            pending.push(entry.path());
+        }
+    }
+    total
+}
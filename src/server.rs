@@ -0,0 +1,416 @@
+use std::convert::Infallible;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use axum::{extract::Request, response::Response, Router};
+use clap::Args;
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+    service::TowerToHyperService,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::task::TaskTracker;
+use tower::Service;
+use tracing::{debug, info, warn};
+
+use crate::hls::{rewrite_uris, segment_file_name};
+use crate::shutdown::Shutdown;
+
+/// How long a client gets to finish the TLS handshake before it is dropped.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The first file descriptor systemd passes with socket activation.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+#[derive(Args, Debug)]
+pub struct ServerArgs {
+    /// Address to listen on
+    #[arg(long, env = "HOST", default_value_t = IpAddr::V4(Ipv4Addr::UNSPECIFIED))]
+    pub host: IpAddr,
+
+    #[arg(long, env = "PORT", default_value_t = 3000)]
+    pub port: u16,
+
+    /// Url players reach this server at, e.g. `https://cdn.example.com/backend`. Playlists point at their segments relative to themselves when unset
+    #[arg(long, env = "PUBLIC_BASE_URL")]
+    pub public_base_url: Option<String>,
+
+    /// Listen on this Unix domain socket instead of `--host` and `--port`, e.g. for a proxy on the same machine
+    #[arg(long, env = "UNIX_SOCKET")]
+    pub unix_socket: Option<PathBuf>,
+
+    /// Octal permissions of the Unix domain socket, whoever may write to it may connect
+    #[arg(long, env = "UNIX_SOCKET_MODE", default_value = "660", value_parser = parse_mode)]
+    pub unix_socket_mode: u32,
+}
+
+impl ServerArgs {
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.host, self.port)
+    }
+
+    /// The sockets to serve on. Sockets passed by systemd socket activation
+    /// take precedence, so restarts don't refuse connections while the
+    /// server is down.
+    pub async fn listeners(&self) -> Result<Vec<Listener>, anyhow::Error> {
+        if let Some(listeners) = activated_listeners()? {
+            return Ok(listeners);
+        }
+
+        let Some(path) = &self.unix_socket else {
+            return Ok(vec![Listener::Tcp(
+                TcpListener::bind(self.socket_addr()).await?,
+            )]);
+        };
+        // A socket left behind by the last run keeps the path taken
+        match tokio::fs::symlink_metadata(path).await {
+            Ok(meta) if meta.file_type().is_socket() => tokio::fs::remove_file(path).await?,
+            Ok(_) => return Err(anyhow!("{} exists and is not a socket", path.display())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err.into()),
+        }
+        let listener = UnixListener::bind(path)?;
+        tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(self.unix_socket_mode))
+            .await?;
+        Ok(vec![Listener::Unix(listener)])
+    }
+}
+
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| format!("{:?} is not an octal file mode like 660", mode))
+}
+
+/// Takes over the sockets systemd passed when `LISTEN_PID` is this process,
+/// see sd_listen_fds(3).
+fn activated_listeners() -> Result<Option<Vec<Listener>>, anyhow::Error> {
+    let pid = std::env::var("LISTEN_PID").ok();
+    if pid.and_then(|pid| pid.parse::<u32>().ok()) != Some(std::process::id()) {
+        return Ok(None);
+    }
+    let count: RawFd = std::env::var("LISTEN_FDS")?.parse()?;
+
+    let listeners = (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(|fd| {
+            // Safety: systemd hands these descriptors to this process only
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            // The copy is close on exec, so ffmpeg doesn't inherit the socket
+            let fd = fd.try_clone()?;
+            let listener = std::os::unix::net::UnixListener::from(fd);
+            if listener.local_addr().is_ok() {
+                listener.set_nonblocking(true)?;
+                return Ok(Listener::Unix(UnixListener::from_std(listener)?));
+            }
+            let listener = std::net::TcpListener::from(OwnedFd::from(listener));
+            listener.set_nonblocking(true)?;
+            Ok(Listener::Tcp(TcpListener::from_std(listener)?))
+        })
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+    if listeners.is_empty() {
+        return Err(anyhow!("Socket activation passed no sockets"));
+    }
+    info!(count = listeners.len(), "Using sockets passed by systemd");
+    Ok(Some(listeners))
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// The TCP port listened on, `None` for a Unix domain socket.
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().ok().map(|addr| addr.port()),
+            Listener::Unix(_) => None,
+        }
+    }
+
+    /// The peer address of a Unix domain socket connection isn't known, so it
+    /// isn't passed on to handlers.
+    async fn accept(&self) -> std::io::Result<(Box<dyn Io>, Option<SocketAddr>)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, remote_addr) = listener.accept().await?;
+                Ok((Box::new(stream), Some(remote_addr)))
+            }
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), None))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => write!(f, "a TCP socket"),
+            },
+            Listener::Unix(listener) => match listener
+                .local_addr()
+                .ok()
+                .and_then(|addr| addr.as_pathname().map(|path| path.to_owned()))
+            {
+                Some(path) => write!(f, "unix:{}", path.display()),
+                None => write!(f, "a Unix domain socket"),
+            },
+        }
+    }
+}
+
+trait Io: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Io for T {}
+
+/// Builds the urls handed to players. Playlists are stored with bare segment
+/// file names and key ids and get their urls when they are served.
+#[derive(Debug, Clone)]
+pub struct PublicUrl(Option<Arc<str>>);
+
+impl PublicUrl {
+    pub fn from_args(args: &ServerArgs) -> Result<PublicUrl, anyhow::Error> {
+        let Some(base) = &args.public_base_url else {
+            return Ok(PublicUrl(None));
+        };
+        if !(base.starts_with("http://") || base.starts_with("https://") || base.starts_with('/')) {
+            return Err(anyhow!(
+                "Public base url {:?} should be an absolute url or path",
+                base
+            ));
+        }
+        Ok(PublicUrl(Some(base.trim_end_matches('/').into())))
+    }
+
+    /// The url of `path` for a response to a request for `from`, both relative
+    /// to the root of the server. Without a base url it is relative to `from`,
+    /// so it works behind proxies that mount the server below a prefix.
+    pub fn url(&self, path: &str, from: &str) -> String {
+        match &self.0 {
+            Some(base) => format!("{}/{}", base, path),
+            None => format!("{}{}", "../".repeat(from.matches('/').count()), path),
+        }
+    }
+
+    /// Points a stream's playlist, served at `from`, at its segments and keys,
+    /// passing the playback token on to them.
+    pub fn playlist(&self, text: &str, from: &str, stream_id: &str, token: Option<&str>) -> String {
+        let url = |path: String| {
+            let url = self.url(&path, from);
+            match token {
+                Some(token) => format!("{}?token={}", url, token),
+                None => url,
+            }
+        };
+        rewrite_uris(
+            text,
+            |uri| url(format!("segment/{}/{}", stream_id, segment_file_name(uri))),
+            |uri| {
+                url(format!(
+                    "stream/{}/key/{}",
+                    stream_id,
+                    segment_file_name(uri)
+                ))
+            },
+        )
+    }
+}
+
+/// Serves `app` until shutdown starts, terminating TLS when an acceptor is
+/// given, then waits for open connections to finish their requests. HTTP/1.1
+/// and HTTP/2 are both served, over TLS the client picks one with ALPN.
+pub async fn serve(listener: Listener, tls: Option<TlsAcceptor>, app: Router, shutdown: Shutdown) {
+    let mut make_service = app
+        .clone()
+        .into_make_service_with_connect_info::<SocketAddr>();
+    let connections = TaskTracker::new();
+    loop {
+        let (stream, remote_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    // Usually out of file descriptors, give connections a moment to close
+                    warn!(%err, "Failed to accept connection");
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = shutdown.cancelled() => break,
+        };
+        let tls = tls.clone();
+        let shutdown = shutdown.clone();
+        match remote_addr {
+            Some(remote_addr) => {
+                let Ok(service) = make_service.call(remote_addr).await;
+                connections.spawn(handle_connection(stream, tls, service, shutdown));
+            }
+            None => {
+                connections.spawn(handle_connection(stream, tls, app.clone(), shutdown));
+            }
+        }
+    }
+
+    connections.close();
+    connections.wait().await;
+}
+
+async fn handle_connection<S>(
+    stream: Box<dyn Io>,
+    tls: Option<TlsAcceptor>,
+    service: S,
+    shutdown: Shutdown,
+) where
+    S: Service<Request<Incoming>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let Some(tls) = tls else {
+        return serve_connection(stream, service, shutdown).await;
+    };
+    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls.accept(stream)).await {
+        Ok(Ok(stream)) => serve_connection(stream, service, shutdown).await,
+        Ok(Err(err)) => debug!(%err, "TLS handshake failed"),
+        Err(_) => debug!("TLS handshake timed out"),
+    }
+}
+
+async fn serve_connection<I, S>(io: I, service: S, shutdown: Shutdown)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Service<Request<Incoming>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let builder = Builder::new(TokioExecutor::new());
+    let connection =
+        builder.serve_connection_with_upgrades(TokioIo::new(io), TowerToHyperService::new(service));
+    tokio::pin!(connection);
+
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = shutdown.cancelled() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(err) = result {
+        debug!(%err, "Connection closed with an error");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn args(public_base_url: Option<&str>) -> ServerArgs {
+        ServerArgs {
+            host: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 3000,
+            public_base_url: public_base_url.map(str::to_string),
+            unix_socket: None,
+            unix_socket_mode: 0o660,
+        }
+    }
+
+    fn public_url(public_base_url: Option<&str>) -> PublicUrl {
+        PublicUrl::from_args(&args(public_base_url)).unwrap()
+    }
+
+    #[test]
+    fn url_is_relative_to_the_request_without_a_base() {
+        let public_url = public_url(None);
+        assert_eq!(
+            public_url.url("segment/abc/000.ts", "stream/abc"),
+            "../segment/abc/000.ts"
+        );
+        assert_eq!(
+            public_url.url("stream/abc", "channels/xyz/live"),
+            "../../stream/abc"
+        );
+        assert_eq!(public_url.url("stream/abc", "streams"), "stream/abc");
+    }
+
+    #[test]
+    fn url_uses_the_base_when_set() {
+        assert_eq!(
+            public_url(Some("https://cdn.example.com/backend/"))
+                .url("segment/abc/000.ts", "stream/abc"),
+            "https://cdn.example.com/backend/segment/abc/000.ts"
+        );
+        assert_eq!(
+            public_url(Some("/backend")).url("stream/abc", "channels/xyz/live"),
+            "/backend/stream/abc"
+        );
+    }
+
+    #[test]
+    fn from_args_refuses_relative_bases() {
+        assert!(PublicUrl::from_args(&args(Some("cdn.example.com"))).is_err());
+    }
+
+    #[test]
+    fn playlist_points_at_segments_and_keys_with_the_token() {
+        let playlist = "#EXTM3U\n#EXT-X-KEY:METHOD=AES-128,URI=\"k1\"\n#EXTINF:4,\n000.ts\n";
+        assert_eq!(
+            public_url(None).playlist(playlist, "stream/abc", "abc", Some("t")),
+            "#EXTM3U\n#EXT-X-KEY:METHOD=AES-128,URI=\"../stream/abc/key/k1?token=t\"\n\
+            #EXTINF:4,\n../segment/abc/000.ts?token=t\n"
+        );
+    }
+
+    #[test]
+    fn parse_mode_reads_octal_modes() {
+        assert_eq!(parse_mode("660"), Ok(0o660));
+        assert_eq!(parse_mode("0777"), Ok(0o777));
+        assert!(parse_mode("1777").is_err());
+        assert!(parse_mode("rw").is_err());
+        assert!(parse_mode("8").is_err());
+    }
+
+    fn socket_args(path: &Path) -> ServerArgs {
+        ServerArgs {
+            unix_socket: Some(path.to_path_buf()),
+            unix_socket_mode: 0o600,
+            ..args(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn listeners_bind_the_unix_socket_with_its_mode() {
+        let path = std::env::temp_dir().join(format!("{}.sock", uuid::Uuid::new_v4()));
+        let listeners = socket_args(&path).listeners().await.unwrap();
+        assert!(matches!(listeners.as_slice(), [Listener::Unix(_)]));
+        assert_eq!(listeners[0].to_string(), format!("unix:{}", path.display()));
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        // A socket left behind by a previous run is replaced
+        drop(listeners);
+        assert!(socket_args(&path).listeners().await.is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn listeners_refuse_to_replace_other_files() {
+        let path = std::env::temp_dir().join(format!("{}.sock", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "not a socket").unwrap();
+        assert!(socket_args(&path).listeners().await.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
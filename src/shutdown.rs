@@ -0,0 +1,151 @@
+use std::time::Duration;
+
+use sqlx::{Pool, Sqlite};
+use tokio_util::{
+    sync::{CancellationToken, WaitForCancellationFutureOwned},
+    task::{task_tracker::TrackedFuture, TaskTracker},
+};
+use tracing::{error, info, warn};
+
+use crate::api::data::StreamStatus;
+use crate::api::upload::finish_stream;
+use crate::events::EventBus;
+
+/// How long in-flight requests, and then ingests that outlive them, each get to
+/// finish after a shutdown signal.
+pub const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// Coordinates stopping the server. Ingests watch it so they can stop reading,
+/// let ffmpeg finalize the playlist and mark their stream as interrupted.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+    tracker: TaskTracker,
+}
+
+impl Shutdown {
+    pub fn is_shutting_down(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    /// Resolves once shutdown has started.
+    pub fn cancelled(&self) -> WaitForCancellationFutureOwned {
+        self.token.clone().cancelled_owned()
+    }
+
+    /// Keeps shutdown waiting for a task that outlives its request, like a
+    /// websocket ingest.
+    pub fn track<F: std::future::Future>(&self, future: F) -> TrackedFuture<F> {
+        self.tracker.track_future(future)
+    }
+
+    /// Waits for SIGINT or SIGTERM and starts shutting down.
+    pub async fn wait_for_signal(self) {
+        let ctrl_c = async {
+            tokio::signal::ctrl_c()
+                .await
+                .expect("The ctrl-c handler should be installed.");
+        };
+
+        #[cfg(unix)]
+        let terminate = async {
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("The SIGTERM handler should be installed.")
+                .recv()
+                .await;
+        };
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+
+        tokio::select! {
+            _ = ctrl_c => (),
+            _ = terminate => (),
+        }
+        info!("Shutting down, no longer accepting ingests");
+        self.token.cancel();
+    }
+
+    /// Waits for tracked tasks, giving up after `timeout`.
+    pub async fn drain(&self, timeout: Duration) {
+        self.tracker.close();
+        if tokio::time::timeout(timeout, self.tracker.wait())
+            .await
+            .is_err()
+        {
+            warn!(
+                remaining = self.tracker.len(),
+                "Timed out waiting for ingests to finish"
+            );
+        }
+    }
+}
+
+/// Marks every stream still being ingested as interrupted, for ingests that did
+/// not finish within the grace period.
+pub async fn interrupt_unfinished(db: &Pool<Sqlite>, events: &EventBus) {
+    let unfinished: Vec<(String,)> =
+        match sqlx::query_as(r#"SELECT `id` FROM `streams` WHERE `status` IN ($1, $2)"#)
+            .bind(StreamStatus::Processing)
+            .bind(StreamStatus::Live)
+            .fetch_all(db)
+            .await
+        {
+            Ok(unfinished) => unfinished,
+            Err(err) => {
+                error!(%err, "Failed to load unfinished streams");
+                return;
+            }
+        };
+
+    for (stream_id,) in unfinished {
+        warn!(stream_id, "Stream was still being ingested at shutdown");
+        finish_stream(db, events, &stream_id, StreamStatus::Interrupted).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn drain_waits_for_tracked_tasks() {
+        let shutdown = Shutdown::default();
+        let finished = Arc::new(AtomicBool::new(false));
+        tokio::spawn(shutdown.track({
+            let finished = finished.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                finished.store(true, Ordering::SeqCst);
+            }
+        }));
+        shutdown.drain(Duration::from_secs(5)).await;
+        assert!(finished.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn drain_gives_up_after_the_timeout() {
+        let shutdown = Shutdown::default();
+        tokio::spawn(shutdown.track(std::future::pending::<()>()));
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            shutdown.drain(Duration::from_millis(50)),
+        )
+        .await
+        .expect("drain should stop waiting after its timeout");
+    }
+
+    #[tokio::test]
+    async fn cancelled_resolves_once_shutting_down() {
+        let shutdown = Shutdown::default();
+        assert!(!shutdown.is_shutting_down());
+        let cancelled = shutdown.cancelled();
+        shutdown.token.cancel();
+        cancelled.await;
+        assert!(shutdown.is_shutting_down());
+    }
+}
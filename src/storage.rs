@@ -0,0 +1,385 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use axum::{async_trait, body::Body};
+use clap::{Args, ValueEnum};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+use crate::ffmpeg_log::LEGACY_LOGS_DIR;
+use crate::hls::{segment_file_name, Playlist};
+use crate::s3::{S3Serve, S3Storage};
+use crate::utils::ResourceDir;
+
+/// How often the playlist of a stream being ingested is checked for new segments.
+const MIRROR_INTERVAL: Duration = Duration::from_secs(1);
+
+pub const PLAYLIST_CONTENT_TYPE: &str = "application/vnd.apple.mpegurl";
+pub const SEGMENT_CONTENT_TYPE: &str = "video/mp2t";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StorageKind {
+    /// Serve straight from the resource directory
+    Local,
+    /// Mirror playlists and segments into an S3-compatible bucket and serve from there
+    S3,
+}
+
+#[derive(Args, Debug)]
+pub struct StorageArgs {
+    /// Where playlists and segments are served from
+    #[arg(long, env = "STORAGE", value_enum, default_value_t = StorageKind::Local)]
+    pub storage: StorageKind,
+
+    /// Base url of the S3 API, e.g. `https://s3.eu-west-1.amazonaws.com` or `http://localhost:9000`
+    #[arg(long, env = "S3_ENDPOINT")]
+    pub s3_endpoint: Option<String>,
+
+    #[arg(long, env = "S3_BUCKET")]
+    pub s3_bucket: Option<String>,
+
+    #[arg(long, env = "S3_REGION", default_value = "us-east-1")]
+    pub s3_region: String,
+
+    #[arg(long, env = "S3_ACCESS_KEY_ID")]
+    pub s3_access_key_id: Option<String>,
+
+    #[arg(long, env = "S3_SECRET_ACCESS_KEY", hide_env_values = true)]
+    pub s3_secret_access_key: Option<String>,
+
+    /// Whether segments are proxied through the server or players are redirected to a presigned url
+    #[arg(long, env = "S3_SERVE", value_enum, default_value_t = S3Serve::Proxy)]
+    pub s3_serve: S3Serve,
+
+    /// Seconds presigned segment urls stay valid for
+    #[arg(long, env = "S3_PRESIGN_EXPIRY", default_value_t = 300)]
+    pub s3_presign_expiry: u64,
+}
+
+/// How a stored object is handed to a client.
+pub enum Served {
+    Body(Body),
+    /// A url the client can fetch the object from itself
+    Redirect(String),
+}
+
+/// Where playlists and segments are served from. ffmpeg always writes into the
+/// resource directory, remote storage gets a copy of everything it writes.
+///
+/// Keys are `{stream_id}/{file_name}`, see [`object_key`].
+#[async_trait]
+pub trait Storage: Send + Sync + std::fmt::Debug {
+    /// Whether objects are the files in the resource directory, so there is
+    /// nothing to upload.
+    fn is_local(&self) -> bool {
+        false
+    }
+
+    async fn put(&self, key: &str, body: Vec<u8>, content_type: &str) -> Result<(), anyhow::Error>;
+
+    /// The whole object, `None` when it does not exist.
+    async fn read(&self, key: &str) -> Result<Option<Vec<u8>>, anyhow::Error>;
+
+    async fn serve(&self, key: &str) -> Result<Option<Served>, anyhow::Error>;
+
+    /// Removes every object of a stream.
+    async fn delete_stream(&self, stream_id: &str) -> Result<(), anyhow::Error>;
+}
+
+pub type SharedStorage = Arc<dyn Storage>;
+
+pub fn from_args(
+    args: &StorageArgs,
+    resource_dir: &ResourceDir,
+) -> Result<SharedStorage, anyhow::Error> {
+    match args.storage {
+        StorageKind::Local => Ok(Arc::new(LocalStorage(resource_dir.clone()))),
+        StorageKind::S3 => {
+            let required = |value: &Option<String>, name: &str| {
+                value
+                    .clone()
+                    .ok_or_else(|| anyhow!("--{} is required with --storage s3", name))
+            };
+            let storage = S3Storage::new(
+                &required(&args.s3_endpoint, "s3-endpoint")?,
+                required(&args.s3_bucket, "s3-bucket")?,
+                args.s3_region.clone(),
+                required(&args.s3_access_key_id, "s3-access-key-id")?,
+                required(&args.s3_secret_access_key, "s3-secret-access-key")?,
+                args.s3_serve,
+                Duration::from_secs(args.s3_presign_expiry),
+            )?;
+            info!(?storage, "Using S3 storage");
+            Ok(Arc::new(storage))
+        }
+    }
+}
+
+/// The key of a file in a stream's directory, `None` when either part could
+/// escape it or the stream id names the directory logs used to be kept in.
+pub fn object_key(stream_id: &str, file_name: &str) -> Option<String> {
+    let is_plain =
+        |part: &str| !part.is_empty() && part != "." && part != ".." && !part.contains(['/', '\\']);
+    if is_plain(stream_id) && stream_id != LEGACY_LOGS_DIR && is_plain(file_name) {
+        Some(format!("{}/{}", stream_id, file_name))
+    } else {
+        None
+    }
+}
+
+/// Serves the files ffmpeg wrote in place.
+#[derive(Debug)]
+pub struct LocalStorage(ResourceDir);
+
+impl LocalStorage {
+    fn path(&self, key: &str) -> PathBuf {
+        self.0.root().join(key)
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    fn is_local(&self) -> bool {
+        true
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        body: Vec<u8>,
+        _content_type: &str,
+    ) -> Result<(), anyhow::Error> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(path, body).await?;
+        Ok(())
+    }
+
+    async fn read(&self, key: &str) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        match tokio::fs::read(self.path(key)).await {
+            Ok(body) => Ok(Some(body)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn serve(&self, key: &str) -> Result<Option<Served>, anyhow::Error> {
+        match tokio::fs::File::open(self.path(key)).await {
+            Ok(file) => Ok(Some(Served::Body(Body::from_stream(
+                tokio_util::io::ReaderStream::new(file),
+            )))),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn delete_stream(&self, stream_id: &str) -> Result<(), anyhow::Error> {
+        match tokio::fs::remove_dir_all(self.0.stream_dir(stream_id)).await {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Copies what ffmpeg writes for a stream into storage while it is being
+/// ingested. Dropping it stops polling after one last copy, [`Mirror::finish`]
+/// also waits for that copy.
+pub struct Mirror {
+    stop: CancellationToken,
+    task: Option<JoinHandle<()>>,
+}
+
+impl Mirror {
+    pub fn start(storage: &SharedStorage, resource_dir: &ResourceDir, stream_id: &str) -> Mirror {
+        let stop = CancellationToken::new();
+        if storage.is_local() {
+            return Mirror { stop, task: None };
+        }
+
+        let storage = storage.clone();
+        let stream_dir = resource_dir.stream_dir(stream_id);
+        let stream_id = stream_id.to_string();
+        let stopped = stop.clone();
+        let task = tokio::spawn(async move {
+            let mut mirrored = Mirrored::default();
+            let mut interval = tokio::time::interval(MIRROR_INTERVAL);
+            loop {
+                let stopping = tokio::select! {
+                    _ = interval.tick() => false,
+                    _ = stopped.cancelled() => true,
+                };
+                if let Err(err) = mirrored
+                    .sync(storage.as_ref(), &stream_dir, &stream_id)
+                    .await
+                {
+                    warn!(%err, stream_id, "Failed to mirror stream, retrying");
+                }
+                if stopping {
+                    break;
+                }
+            }
+        });
+        Mirror {
+            stop,
+            task: Some(task),
+        }
+    }
+
+    pub async fn finish(mut self) {
+        self.stop.cancel();
+        if let Some(task) = self.task.take() {
+            if let Err(err) = task.await {
+                error!(%err, "Stream mirror panicked");
+            }
+        }
+    }
+}
+
+impl Drop for Mirror {
+    fn drop(&mut self) {
+        self.stop.cancel();
+    }
+}
+
+/// What has been copied so far, so each pass only uploads new segments.
+#[derive(Default)]
+struct Mirrored {
+    segments: HashSet<String>,
+    playlist: Option<String>,
+}
+
+impl Mirrored {
+    /// Uploads the segments the playlist lists and then the playlist itself, so
+    /// players never see a segment that is not there yet.
+    async fn sync(
+        &mut self,
+        storage: &dyn Storage,
+        stream_dir: &std::path::Path,
+        stream_id: &str,
+    ) -> Result<(), anyhow::Error> {
+        let text = match tokio::fs::read_to_string(stream_dir.join("index.m3u8")).await {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        if self.playlist.as_ref() == Some(&text) {
+            return Ok(());
+        }
+
+        for segment in Playlist::parse(&text).segments {
+            let file_name = segment_file_name(&segment.uri);
+            if self.segments.contains(file_name) {
+                continue;
+            }
+            let key = object_key(stream_id, file_name)
+                .ok_or_else(|| anyhow!("Invalid segment name {:?}", file_name))?;
+            let body = tokio::fs::read(stream_dir.join(file_name)).await?;
+            storage.put(&key, body, SEGMENT_CONTENT_TYPE).await?;
+            self.segments.insert(file_name.to_string());
+        }
+
+        let key = format!("{}/index.m3u8", stream_id);
+        storage
+            .put(&key, text.clone().into_bytes(), PLAYLIST_CONTENT_TYPE)
+            .await?;
+        self.playlist = Some(text);
+        Ok(())
+    }
+}
+
+/// Copies a finished stream into storage in one go, for streams that were not
+/// written by an ingest, like clips.
+pub async fn upload_stream(
+    storage: &SharedStorage,
+    resource_dir: &ResourceDir,
+    stream_id: &str,
+) -> Result<(), anyhow::Error> {
+    if storage.is_local() {
+        return Ok(());
+    }
+    Mirrored::default()
+        .sync(
+            storage.as_ref(),
+            &resource_dir.stream_dir(stream_id),
+            stream_id,
+        )
+        .await
+}
+
+/// Remote storage kept in memory, without the local copies ffmpeg wrote.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MemoryStorage(std::sync::Mutex<std::collections::HashMap<String, Vec<u8>>>);
+
+#[cfg(test)]
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn put(&self, key: &str, body: Vec<u8>, _: &str) -> Result<(), anyhow::Error> {
+        self.0.lock().unwrap().insert(key.to_string(), body);
+        Ok(())
+    }
+
+    async fn read(&self, key: &str) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        Ok(self.0.lock().unwrap().get(key).cloned())
+    }
+
+    async fn serve(&self, key: &str) -> Result<Option<Served>, anyhow::Error> {
+        Ok(self.read(key).await?.map(|body| Served::Body(body.into())))
+    }
+
+    async fn delete_stream(&self, stream_id: &str) -> Result<(), anyhow::Error> {
+        let prefix = format!("{}/", stream_id);
+        self.0
+            .lock()
+            .unwrap()
+            .retain(|key, _| !key.starts_with(&prefix));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn object_key_joins_plain_names() {
+        assert_eq!(
+            object_key("stream", "000.ts").as_deref(),
+            Some("stream/000.ts")
+        );
+    }
+
+    #[test]
+    fn object_key_refuses_names_that_escape_the_stream() {
+        for (stream_id, file_name) in [
+            ("", "000.ts"),
+            ("stream", ""),
+            ("..", "db"),
+            ("stream", ".."),
+            (".", "000.ts"),
+            ("stream", "../db"),
+            ("a/b", "000.ts"),
+            ("stream", "a\\b"),
+        ] {
+            assert_eq!(
+                object_key(stream_id, file_name),
+                None,
+                "{}/{}",
+                stream_id,
+                file_name
+            );
+        }
+    }
+
+    #[test]
+    fn object_key_refuses_the_old_logs_dir() {
+        assert_eq!(object_key(LEGACY_LOGS_DIR, "stream.log"), None);
+    }
+}
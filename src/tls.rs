@@ -0,0 +1,261 @@
+//! Optional TLS termination. The certificate is reread when its files change,
+//! so renewing it doesn't need a restart, and HTTP/2 is offered over ALPN.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::anyhow;
+use axum::{
+    extract::{Host, State},
+    http::{uri::Authority, StatusCode, Uri},
+    response::Redirect,
+    Router,
+};
+use clap::Args;
+use rustls::{
+    crypto::CryptoProvider,
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::{info, warn};
+
+#[derive(Args, Debug)]
+pub struct TlsArgs {
+    /// PEM certificate chain to serve HTTPS with, needs `--tls-key`. Plain HTTP is served when unset
+    #[arg(long, env = "TLS_CERT_FILE")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the certificate
+    #[arg(long, env = "TLS_KEY_FILE")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Seconds between checks for a renewed certificate
+    #[arg(long, env = "TLS_RELOAD_SECS", default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    pub tls_reload_secs: u64,
+
+    /// Port to listen for plain HTTP on and redirect it to HTTPS
+    #[arg(long, env = "HTTP_REDIRECT_PORT")]
+    pub http_redirect_port: Option<u16>,
+}
+
+pub struct Tls {
+    acceptor: TlsAcceptor,
+    certificate: Arc<Certificate>,
+    reload_interval: Duration,
+}
+
+impl Tls {
+    /// `None` when TLS isn't configured.
+    pub fn from_args(args: &TlsArgs) -> Result<Option<Tls>, anyhow::Error> {
+        let (cert_path, key_path) = match (&args.tls_cert, &args.tls_key) {
+            (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+            (None, None) if args.http_redirect_port.is_some() => {
+                return Err(anyhow!("Redirecting to HTTPS needs a TLS certificate"))
+            }
+            (None, None) => return Ok(None),
+            _ => return Err(anyhow!("TLS needs both a certificate and a private key")),
+        };
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let certificate = Arc::new(Certificate {
+            key: RwLock::new(load_certified_key(&provider, cert_path, key_path)?),
+            modified: RwLock::new(modified(cert_path, key_path)),
+            provider: provider.clone(),
+            cert_path: cert_path.clone(),
+            key_path: key_path.clone(),
+        });
+
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(certificate.clone());
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(Some(Tls {
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            certificate,
+            reload_interval: Duration::from_secs(args.tls_reload_secs),
+        }))
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor.clone()
+    }
+
+    /// Periodically checks the certificate files and switches new connections
+    /// over to a renewed certificate.
+    pub fn spawn_reloader(&self) {
+        let certificate = self.certificate.clone();
+        let reload_interval = self.reload_interval;
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(reload_interval);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let certificate = certificate.clone();
+                if let Err(err) = tokio::task::spawn_blocking(move || certificate.reload()).await {
+                    warn!(%err, "TLS certificate reload panicked");
+                }
+            }
+        });
+    }
+}
+
+/// The certificate handed to every handshake, swapped out when it is renewed.
+#[derive(Debug)]
+struct Certificate {
+    key: RwLock<Arc<CertifiedKey>>,
+    modified: RwLock<Option<SystemTime>>,
+    provider: Arc<CryptoProvider>,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+}
+
+impl Certificate {
+    fn reload(&self) {
+        let modified = modified(&self.cert_path, &self.key_path);
+        if modified == *self.modified.read().unwrap() {
+            return;
+        }
+
+        // The certificate and key are usually replaced one after the other, a
+        // mismatch is picked up again on the next check.
+        match load_certified_key(&self.provider, &self.cert_path, &self.key_path) {
+            Ok(key) => {
+                *self.key.write().unwrap() = key;
+                *self.modified.write().unwrap() = modified;
+                info!("Reloaded TLS certificate");
+            }
+            Err(err) => warn!(%err, "Failed to reload TLS certificate, keeping the old one"),
+        }
+    }
+}
+
+impl ResolvesServerCert for Certificate {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().unwrap().clone())
+    }
+}
+
+fn load_certified_key(
+    provider: &CryptoProvider,
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<Arc<CertifiedKey>, anyhow::Error> {
+    let certs = rustls_pemfile::certs(&mut std::fs::read(cert_path)?.as_slice())
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(anyhow!("No certificate found in {}", cert_path.display()));
+    }
+    let key = rustls_pemfile::private_key(&mut std::fs::read(key_path)?.as_slice())?
+        .ok_or_else(|| anyhow!("No private key found in {}", key_path.display()))?;
+
+    let key = CertifiedKey::new(certs, provider.key_provider.load_private_key(key)?);
+    key.keys_match()?;
+    Ok(Arc::new(key))
+}
+
+/// When either file last changed, `None` when it can't be told.
+fn modified(cert_path: &Path, key_path: &Path) -> Option<SystemTime> {
+    let modified = |path: &Path| {
+        std::fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .ok()
+    };
+    Some(modified(cert_path)?.max(modified(key_path)?))
+}
+
+/// Sends every plain HTTP request to the same url on the HTTPS port.
+pub fn redirect(https_port: u16) -> Router {
+    Router::new()
+        .fallback(redirect_to_https)
+        .with_state(https_port)
+}
+
+async fn redirect_to_https(
+    Host(host): Host,
+    State(https_port): State<u16>,
+    uri: Uri,
+) -> Result<Redirect, StatusCode> {
+    let authority: Authority = host.parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    let url = match https_port {
+        443 => format!("https://{}{}", authority.host(), path),
+        port => format!("https://{}:{}{}", authority.host(), port, path),
+    };
+    Ok(Redirect::permanent(&url))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::header, http::Request};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    fn args(cert: Option<&str>, key: Option<&str>, redirect: Option<u16>) -> TlsArgs {
+        TlsArgs {
+            tls_cert: cert.map(PathBuf::from),
+            tls_key: key.map(PathBuf::from),
+            tls_reload_secs: 60,
+            http_redirect_port: redirect,
+        }
+    }
+
+    #[test]
+    fn from_args_needs_a_certificate_and_a_key() {
+        assert!(Tls::from_args(&args(None, None, None)).unwrap().is_none());
+        assert!(Tls::from_args(&args(Some("cert.pem"), None, None)).is_err());
+        assert!(Tls::from_args(&args(None, Some("key.pem"), None)).is_err());
+        assert!(Tls::from_args(&args(None, None, Some(80))).is_err());
+        assert!(Tls::from_args(&args(Some("/nonexistent"), Some("/nonexistent"), None)).is_err());
+    }
+
+    async fn location(https_port: u16, host: &str, uri: &str) -> String {
+        let response = redirect(https_port)
+            .oneshot(
+                Request::builder()
+                    .uri(uri)
+                    .header(header::HOST, host)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        response.headers()[header::LOCATION]
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn redirect_keeps_the_host_path_and_query() {
+        assert_eq!(
+            location(443, "example.com:8080", "/stream/abc?token=t").await,
+            "https://example.com/stream/abc?token=t"
+        );
+        assert_eq!(
+            location(8443, "example.com", "/streams").await,
+            "https://example.com:8443/streams"
+        );
+    }
+
+    #[tokio::test]
+    async fn redirect_rejects_invalid_hosts() {
+        let response = redirect(443)
+            .oneshot(
+                Request::builder()
+                    .uri("/")
+                    .header(header::HOST, "not a host")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,24 @@
+use clap::Args;
+
+/// How ffmpeg encodes uploads, live ingests and frame accurate clips.
+#[derive(Args, Debug, Clone)]
+pub struct TranscodeArgs {
+    /// ffmpeg video encoder, e.g. `libx264` where `h264_videotoolbox` is not available
+    #[arg(long, env = "VIDEO_CODEC", default_value = "h264_videotoolbox")]
+    pub video_codec: String,
+
+    /// Target segment length in seconds, segments are cut at the next keyframe after it
+    #[arg(long, env = "HLS_TIME", default_value_t = 2, value_parser = clap::value_parser!(u32).range(1..))]
+    pub hls_time: u32,
+
+    /// Seconds between forced keyframes
+    #[arg(long, env = "KEYFRAME_INTERVAL", default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    pub keyframe_interval: u32,
+}
+
+impl TranscodeArgs {
+    /// The `-force_key_frames` expression for the keyframe interval.
+    pub fn force_key_frames(&self) -> String {
+        format!("expr:gte(t,n_forced*{})", self.keyframe_interval)
+    }
+}
@@ -1,52 +1,10 @@
 use anyhow::anyhow;
-use axum::{
-    async_trait,
-    extract::{FromRef, FromRequestParts},
-    http::request::Parts,
-};
+use axum::http::{header, HeaderMap};
 use hyper::StatusCode;
 use sqlx::SqlitePool;
-use std::{net::SocketAddr, path::PathBuf};
+use std::path::{Path, PathBuf};
 use tokio::fs;
-use tracing::{error, info, level_filters::LevelFilter};
-use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Registry};
-
-pub fn init_logger() {
-    let env_filter = EnvFilter::builder()
-        .with_default_directive(LevelFilter::DEBUG.into())
-        .from_env()
-        .expect("Failed to create env filter invalid RUST_LOG env var");
-
-    let registry = Registry::default()
-        .with(env_filter)
-        .with(fmt::layer().compact().without_time());
-
-    registry.try_init().expect("Failed to initialize tracing");
-}
-
-pub fn get_socket_addr() -> Result<SocketAddr, anyhow::Error> {
-    let port = match std::env::var("PORT") {
-        Ok(port_env) => port_env,
-        Err(std::env::VarError::NotPresent) => String::from("3000"),
-        Err(err) => {
-            return Err(anyhow::Error::from(err).context("PORT env var is not unicode"));
-        }
-    };
-
-    let host = match std::env::var("HOST") {
-        Ok(port_env) => port_env,
-        Err(std::env::VarError::NotPresent) => String::from("0.0.0.0"),
-        Err(err) => {
-            return Err(anyhow::Error::from(err).context("HOST env var is not unicode"));
-        }
-    };
-
-    format!("{}:{}", host, port)
-        .parse::<SocketAddr>()
-        .map_err(|err| {
-            anyhow::Error::from(err).context("Failed to parse HOST and PORT into socket address")
-        })
-}
+use tracing::{error, info};
 
 pub async fn get_db(sqlite_path: &PathBuf) -> Result<SqlitePool, anyhow::Error> {
     if !sqlite_path.exists() {
@@ -69,31 +27,54 @@ pub async fn get_db(sqlite_path: &PathBuf) -> Result<SqlitePool, anyhow::Error>
     Ok(pool)
 }
 
-pub struct DatabaseConnection(pub sqlx::SqlitePool);
+/// The directory every stream's playlist and segments are written into, and
+/// the one ffmpeg's output is kept in.
+#[derive(Debug, Clone)]
+pub struct ResourceDir {
+    root: PathBuf,
+    logs: PathBuf,
+}
 
-#[async_trait]
-impl<S> FromRequestParts<S> for DatabaseConnection
-where
-    SqlitePool: FromRef<S>,
-    S: Send + Sync,
-{
-    type Rejection = (StatusCode, String);
+impl ResourceDir {
+    pub fn new(root: PathBuf, logs: PathBuf) -> ResourceDir {
+        ResourceDir { root, logs }
+    }
 
-    async fn from_request_parts(_parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        let pool = SqlitePool::from_ref(state);
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn stream_dir(&self, stream_id: &str) -> PathBuf {
+        self.root.join(stream_id)
+    }
 
-        Ok(Self(pool))
+    /// Where ffmpeg's output for a stream is kept, outside the resource dir so
+    /// it is never served and survives failed transcodes being cleaned up.
+    pub fn log_path(&self, stream_id: &str) -> PathBuf {
+        self.logs.join(format!("{}.log", stream_id))
+    }
+
+    pub fn logs_dir(&self) -> &Path {
+        &self.logs
     }
 }
 
-/// Utility function for mapping any error into a `500 Internal Server Error`
-/// response.
-pub fn internal_error<E>(err: E) -> (StatusCode, String)
-where
-    E: std::error::Error,
-{
-    error!(%err, "Internal error: {:?}", err);
-    (StatusCode::INTERNAL_SERVER_ERROR, "err".to_string())
+/// A migrated in-memory database. It lives on a single connection that is
+/// never closed, since every connection would get its own empty database.
+#[cfg(test)]
+pub async fn test_db() -> SqlitePool {
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect("sqlite::memory:")
+        .await
+        .expect("The test database should be created.");
+    sqlx::migrate!("src/migrations")
+        .run(&pool)
+        .await
+        .expect("The migrations should run.");
+    pool
 }
 
 pub fn database_error(err: sqlx::Error) -> StatusCode {
@@ -121,3 +102,62 @@ pub fn format_bytes(bytes: i64) -> String {
 
     format!("{} B", bytes)
 }
+
+/// Parses a byte count with an optional `K`, `M`, `G` or `T` suffix.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (number, multiplier) = match size.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&size[..i], 1024),
+        Some((i, 'M' | 'm')) => (&size[..i], 1024 * 1024),
+        Some((i, 'G' | 'g')) => (&size[..i], 1024 * 1024 * 1024),
+        Some((i, 'T' | 't')) => (&size[..i], 1024 * 1024 * 1024 * 1024),
+        _ => (size, 1),
+    };
+    let number = number
+        .trim()
+        .parse::<u64>()
+        .map_err(|err| format!("invalid size {:?}: {}", size, err))?;
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("invalid size {:?}: number too large", size))
+}
+
+pub fn get_cookie(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(cookie_name, _)| *cookie_name == name)
+        .map(|(_, value)| value.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_size_reads_suffixes() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("4k"), Ok(4 * 1024));
+        assert_eq!(parse_size(" 2 M "), Ok(2 * 1024 * 1024));
+        assert_eq!(parse_size("10G"), Ok(10 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("1T"), Ok(1024 * 1024 * 1024 * 1024));
+    }
+
+    #[test]
+    fn parse_size_rejects_malformed_sizes() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("-1K").is_err());
+        assert!(parse_size("1.5G").is_err());
+        assert!(parse_size("10P").is_err());
+    }
+
+    #[test]
+    fn parse_size_rejects_overflowing_sizes() {
+        assert!(parse_size("99999999999T").is_err());
+        assert_eq!(parse_size(&u64::MAX.to_string()), Ok(u64::MAX));
+    }
+}
@@ -0,0 +1,220 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::http::HeaderMap;
+use sqlx::{Pool, Sqlite};
+use tracing::{debug, error};
+
+/// A viewer that has not requested the playlist or a segment for this long has
+/// stopped watching.
+const VIEWER_TIMEOUT: Duration = Duration::from_secs(30);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+pub const VIEWER_COOKIE: &str = "viewer_session";
+
+/// Who a playback request is from. Players get a session cookie on their first
+/// playlist request, until then and for players that don't keep cookies the
+/// client address stands in for it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Viewer {
+    /// The server-issued session cookie
+    pub session: Option<String>,
+    pub address: Option<IpAddr>,
+}
+
+impl Viewer {
+    pub fn from_request(headers: &HeaderMap, client_ip: Option<IpAddr>) -> Viewer {
+        Viewer {
+            session: crate::utils::get_cookie(headers, VIEWER_COOKIE),
+            address: client_ip,
+        }
+    }
+
+    fn session_key(&self) -> Option<String> {
+        self.session
+            .as_ref()
+            .map(|session| format!("session:{}", session))
+    }
+
+    fn address_key(&self) -> Option<String> {
+        self.address.map(|address| format!("address:{}", address))
+    }
+}
+
+/// Live concurrent viewer counts, keyed by stream and then viewer session.
+#[derive(Debug, Clone, Default)]
+pub struct ViewerTracker(Arc<Mutex<HashMap<String, HashMap<String, Instant>>>>);
+
+impl ViewerTracker {
+    /// Records activity for a viewer, persisting the view and peak viewer count
+    /// the first time the viewer is seen.
+    pub async fn touch(&self, db: &Pool<Sqlite>, stream_id: &str, viewer: &Viewer) {
+        let Some(key) = viewer.session_key().or_else(|| viewer.address_key()) else {
+            return;
+        };
+        let concurrent = {
+            let mut streams = self.0.lock().expect("viewer lock poisoned");
+            let sessions = streams.entry(stream_id.to_string()).or_default();
+            // Viewers the sweeper hasn't got to yet have already left
+            sessions.retain(|_, last_seen| last_seen.elapsed() < VIEWER_TIMEOUT);
+            let is_new = sessions.insert(key, Instant::now()).is_none();
+            if !is_new {
+                return;
+            }
+            // A player that just got its cookie was counted by its address
+            if viewer.session.is_some() {
+                if let Some(address_key) = viewer.address_key() {
+                    if sessions.remove(&address_key).is_some() {
+                        return;
+                    }
+                }
+            }
+            sessions.len() as i64
+        };
+
+        debug!(stream_id, concurrent, "New viewer");
+        if let Err(err) = sqlx::query(
+            r#"UPDATE `streams` SET `totalViews` = `totalViews` + 1, `peakViewers` = max(`peakViewers`, $1) WHERE `id` = $2"#,
+        )
+        .bind(concurrent)
+        .bind(stream_id)
+        .execute(db)
+        .await
+        {
+            error!(%err, "Failed to record view");
+        }
+    }
+
+    pub fn current(&self, stream_id: &str) -> i64 {
+        let streams = self.0.lock().expect("viewer lock poisoned");
+        streams
+            .get(stream_id)
+            .map(|sessions| {
+                sessions
+                    .values()
+                    .filter(|last_seen| last_seen.elapsed() < VIEWER_TIMEOUT)
+                    .count() as i64
+            })
+            .unwrap_or(0)
+    }
+
+    fn sweep(&self) {
+        let mut streams = self.0.lock().expect("viewer lock poisoned");
+        for sessions in streams.values_mut() {
+            sessions.retain(|_, last_seen| last_seen.elapsed() < VIEWER_TIMEOUT);
+        }
+        streams.retain(|_, sessions| !sessions.is_empty());
+    }
+
+    /// Periodically forgets viewers that have gone away.
+    pub fn spawn_sweeper(&self) {
+        let tracker = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                tracker.sweep();
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test_db;
+
+    async fn views(db: &Pool<Sqlite>) -> (i64, i64) {
+        sqlx::query_as(r#"SELECT `totalViews`, `peakViewers` FROM `streams` WHERE `id` = 'stream'"#)
+            .fetch_one(db)
+            .await
+            .unwrap()
+    }
+
+    async fn stream_db() -> Pool<Sqlite> {
+        let db = test_db().await;
+        sqlx::query(
+            r#"insert into streams (id, name, description, startTime, width, height) values ('stream', 'Stream', '', $1, 1920, 1080)"#,
+        )
+        .bind(chrono::Utc::now())
+        .execute(&db)
+        .await
+        .unwrap();
+        db
+    }
+
+    fn viewer(session: Option<&str>, address: &str) -> Viewer {
+        Viewer {
+            session: session.map(str::to_string),
+            address: Some(address.parse().unwrap()),
+        }
+    }
+
+    #[tokio::test]
+    async fn repeat_polls_are_one_view() {
+        let db = stream_db().await;
+        let tracker = ViewerTracker::default();
+        for _ in 0..3 {
+            tracker
+                .touch(&db, "stream", &viewer(None, "10.0.0.1"))
+                .await;
+            tracker
+                .touch(&db, "stream", &viewer(Some("a"), "10.0.0.2"))
+                .await;
+        }
+        assert_eq!(views(&db).await, (2, 2));
+        assert_eq!(tracker.current("stream"), 2);
+    }
+
+    #[tokio::test]
+    async fn getting_a_cookie_is_not_a_new_view() {
+        let db = stream_db().await;
+        let tracker = ViewerTracker::default();
+        tracker
+            .touch(&db, "stream", &viewer(None, "10.0.0.1"))
+            .await;
+        tracker
+            .touch(&db, "stream", &viewer(Some("a"), "10.0.0.1"))
+            .await;
+        assert_eq!(views(&db).await, (1, 1));
+        assert_eq!(tracker.current("stream"), 1);
+    }
+
+    #[tokio::test]
+    async fn sessions_behind_one_address_are_separate_viewers() {
+        let db = stream_db().await;
+        let tracker = ViewerTracker::default();
+        tracker
+            .touch(&db, "stream", &viewer(Some("a"), "10.0.0.1"))
+            .await;
+        tracker
+            .touch(&db, "stream", &viewer(Some("b"), "10.0.0.1"))
+            .await;
+        assert_eq!(views(&db).await, (2, 2));
+    }
+
+    #[tokio::test]
+    async fn the_session_cookie_identifies_the_viewer() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            axum::http::header::COOKIE,
+            format!("{}=abc", VIEWER_COOKIE).parse().unwrap(),
+        );
+        let address = Some("10.0.0.1".parse().unwrap());
+        assert_eq!(
+            Viewer::from_request(&headers, address),
+            Viewer {
+                session: Some("abc".to_string()),
+                address,
+            }
+        );
+        assert_eq!(
+            Viewer::from_request(&HeaderMap::new(), None),
+            Viewer::default()
+        );
+    }
+}
@@ -0,0 +1,252 @@
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::Sha256;
+use sqlx::{Pool, Sqlite};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info, instrument, warn};
+
+use crate::events::{EventBus, StreamEvent};
+
+const MAX_ATTEMPTS: i64 = 6;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const EVENT_NAMES: [&str; 6] = [
+    "stream-created",
+    "went-live",
+    "ended",
+    "deleted",
+    "restored",
+    "updated",
+];
+
+/// Forwards every event on the bus to the webhooks subscribed to it. Events of
+/// unlisted and private streams are sent too, which is why only admins can
+/// register webhooks.
+pub fn spawn_dispatcher(db: Pool<Sqlite>, events: &EventBus) {
+    let mut receiver = events.subscribe();
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .expect("The webhook http client should be created.");
+
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => dispatch(&db, &client, event).await,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "Webhook dispatcher lagged behind, dropping events");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+#[instrument(skip(db, client))]
+async fn dispatch(db: &Pool<Sqlite>, client: &reqwest::Client, event: StreamEvent) {
+    let webhooks: Vec<(String, String, String, String)> =
+        match sqlx::query_as(r#"SELECT `id`, `url`, `secret`, `events` FROM `webhooks`"#)
+            .fetch_all(db)
+            .await
+        {
+            Ok(webhooks) => webhooks,
+            Err(err) => {
+                error!(%err, "Failed to load webhooks");
+                return;
+            }
+        };
+
+    for (webhook_id, url, secret, subscribed_events) in webhooks {
+        if !is_subscribed(&subscribed_events, event.name()) {
+            continue;
+        }
+
+        let delivery_id = uuid::Uuid::new_v4().to_string();
+        let now = chrono::Utc::now();
+        let payload = json!({
+            "id": delivery_id,
+            "event": event.name(),
+            "createdAt": now,
+            "data": event,
+        })
+        .to_string();
+
+        if let Err(err) = sqlx::query(
+            r#"insert into webhookDeliveries (id, webhookId, event, payload, status, createdAt, updatedAt) values ($1, $2, $3, $4, 'pending', $5, $5)"#,
+        )
+        .bind(&delivery_id)
+        .bind(&webhook_id)
+        .bind(event.name())
+        .bind(&payload)
+        .bind(now)
+        .execute(db)
+        .await
+        {
+            error!(%err, webhook_id, "Failed to create webhook delivery");
+            continue;
+        }
+
+        tokio::spawn(deliver(
+            db.clone(),
+            client.clone(),
+            delivery_id,
+            url,
+            secret,
+            payload,
+        ));
+    }
+}
+
+#[instrument(skip(db, client, secret, payload))]
+async fn deliver(
+    db: Pool<Sqlite>,
+    client: reqwest::Client,
+    delivery_id: String,
+    url: String,
+    secret: String,
+    payload: String,
+) {
+    let mut backoff = INITIAL_BACKOFF;
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        let timestamp = chrono::Utc::now().timestamp().to_string();
+        let signature = sign(&secret, &timestamp, &payload);
+
+        let result = client
+            .post(&url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("X-Webhook-Id", &delivery_id)
+            .header("X-Webhook-Timestamp", &timestamp)
+            .header("X-Webhook-Signature", format!("sha256={}", signature))
+            .body(payload.clone())
+            .send()
+            .await;
+
+        let (response_status, err) = match result {
+            Ok(res) if res.status().is_success() => {
+                info!(attempt, "Delivered webhook");
+                record_attempt(
+                    &db,
+                    &delivery_id,
+                    attempt,
+                    "succeeded",
+                    Some(res.status()),
+                    None,
+                )
+                .await;
+                return;
+            }
+            Ok(res) => (
+                Some(res.status()),
+                format!("Unexpected response status {}", res.status()),
+            ),
+            Err(err) => (None, err.to_string()),
+        };
+
+        if attempt == MAX_ATTEMPTS {
+            error!(attempt, err, "Giving up on webhook delivery");
+            record_attempt(
+                &db,
+                &delivery_id,
+                attempt,
+                "failed",
+                response_status,
+                Some(err),
+            )
+            .await;
+            return;
+        }
+
+        warn!(attempt, err, ?backoff, "Webhook delivery failed, retrying");
+        record_attempt(
+            &db,
+            &delivery_id,
+            attempt,
+            "pending",
+            response_status,
+            Some(err),
+        )
+        .await;
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+async fn record_attempt(
+    db: &Pool<Sqlite>,
+    delivery_id: &str,
+    attempt: i64,
+    status: &str,
+    response_status: Option<reqwest::StatusCode>,
+    err: Option<String>,
+) {
+    if let Err(err) = sqlx::query(
+        r#"UPDATE `webhookDeliveries` SET `status` = $1, `attempts` = $2, `responseStatus` = $3, `error` = $4, `updatedAt` = $5 WHERE `id` = $6"#,
+    )
+    .bind(status)
+    .bind(attempt)
+    .bind(response_status.map(|status| status.as_u16()))
+    .bind(err)
+    .bind(chrono::Utc::now())
+    .bind(delivery_id)
+    .execute(db)
+    .await
+    {
+        error!(%err, "Failed to record webhook delivery attempt");
+    }
+}
+
+/// Hex encoded HMAC-SHA256 of `{timestamp}.{payload}`, receivers recompute it to
+/// check a delivery came from us and was not replayed.
+pub fn sign(secret: &str, timestamp: &str, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC can take a key of any size");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(payload.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+fn is_subscribed(subscribed_events: &str, event_name: &str) -> bool {
+    subscribed_events
+        .split(',')
+        .any(|subscribed| subscribed == "*" || subscribed == event_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_is_an_hmac_of_the_timestamp_and_payload() {
+        assert_eq!(
+            sign("whsec", "1700000000", r#"{"type":"ended"}"#),
+            "f19f94d042ce7df81e20d431a6b4f0b77e841c2e44af8418cdc430733b28709f"
+        );
+    }
+
+    #[test]
+    fn sign_covers_the_timestamp() {
+        let payload = r#"{"type":"ended"}"#;
+        assert_ne!(
+            sign("whsec", "1700000000", payload),
+            sign("whsec", "1700000001", payload)
+        );
+        assert_ne!(
+            sign("whsec", "1700000000", payload),
+            sign("other", "1700000000", payload)
+        );
+    }
+
+    #[test]
+    fn is_subscribed_matches_names_and_wildcard() {
+        assert!(is_subscribed("ended,deleted", "deleted"));
+        assert!(is_subscribed("*", "went-live"));
+        assert!(!is_subscribed("ended", "went-live"));
+        assert!(!is_subscribed("ended-ish", "ended"));
+    }
+}